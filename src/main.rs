@@ -1,300 +1,7987 @@
+use std::borrow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi;
 use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal as _, Write as _};
+use std::panic;
+use std::path;
+use std::process;
+use std::str;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time;
 
 use anyhow::Context as _;
+use clap::Parser as _;
 use hex_literal::hex;
 
+/// The version of the JSON output of the device listing, increased for incompatible changes
+const SCHEMA_VERSION: u32 = 2;
+
+// Exit codes of the device listing:
+//   0  no problems found
+//   1  the diagnosis failed, or warnings were emitted with --check-only or --fail-on-warning
+//   2  an expected device is missing, or the diagnosis failed with --check-only
+//   3  libusb failed to enumerate the USB devices (only with --check-only)
+//   4  only bootloader devices were found, but no firmware devices or readers
+const EXIT_BOOTLOADER_ONLY: i32 = 4;
+
 const VID_FIRMWARE: u16 = 0x1209;
 const PID_FIRMWARE: u16 = 0xbeee;
+const USB_CLASS_SMART_CARD: u8 = 0x0b;
+
+// Used by lpc55::bootloader::Bootloader::list to avoid querying unrelated HID devices
+const BOOTLOADER_MANUFACTURER: &str = "NXP SEMICONDUCTOR INC.";
+const BOOTLOADER_PRODUCT: &str = "USB COMPOSITE DEVICE";
+
+/// Appended to the note about unavailable string descriptors
+#[cfg(windows)]
+const STRING_DESCRIPTORS_HINT: &str = " (install WinUSB driver?)";
+#[cfg(not(windows))]
+const STRING_DESCRIPTORS_HINT: &str = "";
+
+/// USB IDs of devices in a recovery mode, e. g. after a failed update
+const RECOVERY_DEVICES: &[(u16, u16, &str)] = &[(0x20a0, 0x42e8, "nRF52 bootloader")];
+const RECOVERY_HINT: &str = "Install the firmware again, e. g. with nitropy nk3 update.";
 
 const FIRMWARE_READER_NAME: &[u8] = b"SoloKeys Solo 2 [CCID/ICCD Interface]";
 
 const AID_ADMIN: &[u8] = &hex!("A00000084700000001");
 const AID_PROVISIONER: &[u8] = &hex!("A00000084701000001");
+const AID_FIDO: &[u8] = &hex!("A0000006472F0001");
+const AID_OPENPGP: &[u8] = &hex!("D27600012401");
+const AID_PIV: &[u8] = &hex!("A000000308");
+const AID_SECRETS: &[u8] = &hex!("A0000005272101");
+const AID_NDEF: &[u8] = &hex!("D2760000850101");
 
-#[derive(Clone, Debug)]
-enum Device {
-    Bootloader { vid: u16, pid: u16, uuid: u128 },
-    Firmware { bus: u8, address: u8 },
+/// The admin config keys that control touch confirmation
+const TOUCH_CONFIG_KEYS: &[&str] = &["fido.disable_skip_up_timeout"];
+
+/// The admin config keys that are always read and their descriptions
+const KNOWN_CONFIG_KEYS: &[(&str, &str)] = &[
+    (
+        "fido.disable_skip_up_timeout",
+        "FIDO2 touch timeout after boot disabled",
+    ),
+    (
+        "opcard.use_se050_backend",
+        "OpenPGP keys stored in the secure element",
+    ),
+];
+
+// Flags in the init status byte returned by the admin status command
+const INIT_STATUS_INTERNAL_FLASH_ERROR: u8 = 0b0010;
+const INIT_STATUS_EXTERNAL_FLASH_ERROR: u8 = 0b0100;
+
+// Directories that are searched for udev rules for the firmware devices
+#[cfg(target_os = "linux")]
+const UDEV_RULES_DIRS: &[&str] = &[
+    "/etc/udev/rules.d",
+    "/usr/lib/udev/rules.d",
+    "/lib/udev/rules.d",
+];
+
+// The Info.plist of the CCID driver that has to list the firmware devices
+#[cfg(target_os = "macos")]
+const CCID_INFO_PLIST: &str =
+    "/usr/libexec/SmartCardServices/drivers/ifd-ccid.bundle/Contents/Info.plist";
+
+#[cfg(all(feature = "ccid", target_os = "linux"))]
+const PCSC_REMEDIATION: &str =
+    "Install and start pcscd, e. g. with `systemctl start pcscd.socket`.";
+#[cfg(all(feature = "ccid", target_os = "macos"))]
+const PCSC_REMEDIATION: &str = "Check that the CryptoTokenKit smartcard service is running.";
+#[cfg(all(feature = "ccid", target_os = "windows"))]
+const PCSC_REMEDIATION: &str =
+    "Start the Smart Card service (SCardSvr), e. g. with `sc start SCardSvr`.";
+#[cfg(all(
+    feature = "ccid",
+    not(any(target_os = "linux", target_os = "macos", target_os = "windows"))
+))]
+const PCSC_REMEDIATION: &str = "Install and start a PC/SC service.";
+
+#[cfg(not(feature = "ccid"))]
+const CCID_UNSUPPORTED: &str = "CCID support not compiled in";
+
+// PC/SC part 10 reader features (control codes are converted into the platform-specific DWORD)
+#[cfg(feature = "ccid")]
+const CM_IOCTL_GET_FEATURE_REQUEST: u32 = 3400;
+#[cfg(feature = "ccid")]
+const FEATURE_GET_TLV_PROPERTIES: u8 = 0x12;
+#[cfg(feature = "ccid")]
+const PROPERTY_MAX_APDU_DATA_SIZE: u8 = 0x0A;
+
+/// Known firmware versions and their status.  Versions that do not match any entry are
+/// reported as unknown.  Versions with an issue are reported as known buggy.  Update this table
+/// for new releases and when issues become known.
+const KNOWN_VERSIONS: &[KnownVersion] = &[
+    KnownVersion {
+        min: Version::new(1, 8, 0),
+        max: Version::new(1, 8, u32::MAX),
+        status: VersionStatus::Latest,
+        issue: None,
+    },
+    KnownVersion {
+        min: Version::new(1, 0, 0),
+        max: Version::new(1, 7, u32::MAX),
+        status: VersionStatus::Supported,
+        issue: None,
+    },
+    // Pre-release versions
+    KnownVersion {
+        min: Version::new(0, 0, 0),
+        max: Version::new(0, u32::MAX, u32::MAX),
+        status: VersionStatus::Eol,
+        issue: None,
+    },
+];
+
+/// Known bootloader versions as reported by lpc55 and the firmware versions that work with
+/// them.  The compatibility of bootloader versions that are not in this table is unknown.
+const KNOWN_BOOTLOADERS: &[KnownBootloader] = &[
+    // The ROM bootloader of the LPC55S69
+    KnownBootloader {
+        version: "K3.0.0",
+        min_firmware: Version::new(1, 0, 0),
+        max_firmware: Version::new(u32::MAX, u32::MAX, u32::MAX),
+    },
+];
+
+/// All applets known to this tool, in probing order.
+const KNOWN_APPLETS: &[(&str, &[u8])] = &[
+    ("admin", AID_ADMIN),
+    ("provisioner", AID_PROVISIONER),
+    ("fido2", AID_FIDO),
+    ("openpgp", AID_OPENPGP),
+    ("piv", AID_PIV),
+    ("secrets", AID_SECRETS),
+    ("ndef", AID_NDEF),
+];
+
+/// Writes a log message to stderr with --verbose and to the --log-file.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        log_message(format_args!($($arg)*))
+    };
+}
+
+/// Display information about connected NK3 devices
+#[derive(Debug, clap::Parser)]
+#[command(version, about)]
+struct Args {
+    /// Check that a device with this UUID is connected (can be repeated)
+    #[arg(long = "expect-uuid", value_name = "UUID")]
+    expect_uuids: Vec<Uuid>,
+
+    /// Check that the devices with the UUIDs listed in this file (one per line) are connected
+    #[arg(long, value_name = "PATH")]
+    expect_uuids_file: Option<path::PathBuf>,
+
+    /// Report the UUIDs that were added or removed since the last run with this state file
+    /// and update it
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<path::PathBuf>,
+
+    /// Exit with an error if any warnings were emitted, only counting the warnings that are
+    /// shown with --min-severity
+    #[arg(long)]
+    fail_on_warning: bool,
+
+    /// Abort with an error as soon as a firmware reader cannot be probed or a firmware
+    /// device is unreachable, e. g. on an assembly line
+    ///
+    /// Unlike --fail-on-warning, which is evaluated after the full scan, this stops the scan
+    /// at the first such problem and does not print any results.
+    #[arg(long)]
+    strict: bool,
+
+    /// Only send the admin commands that are supported by old firmware versions
+    ///
+    /// This skips the queries for the build info, secure boot, initialization status and
+    /// config.  It is enabled automatically for devices that do not support the version
+    /// command.
+    #[arg(long)]
+    compat_mode: bool,
+
+    /// Only show warnings with at least this severity
+    #[arg(long, value_enum, value_name = "LEVEL", default_value_t = Severity::Info)]
+    min_severity: Severity,
+
+    /// Do not warn about devices with provisioner firmware, e. g. on provisioning stations
+    #[arg(long)]
+    allow_provisioner: bool,
+
+    /// Connect to a remote pcscd at this address instead of the local one (pcsc-lite only)
+    ///
+    /// pcscd only listens on a local socket, so it has to be exposed on the remote host, e. g.
+    /// with `socat TCP-LISTEN:35963,reuseaddr,fork UNIX-CONNECT:/run/pcscd/pcscd.comm`.
+    /// The remote pcscd must use the same protocol version as the local pcsc-lite.  To use
+    /// another local socket, set PCSCLITE_CSOCK_NAME instead.
+    ///
+    /// USB devices are still enumerated on the local host.
+    #[arg(long, value_name = "HOST:PORT")]
+    pcsc_remote: Option<String>,
+
+    /// Continue with the USB results if the pcsc context cannot be established
+    #[arg(long)]
+    ccid_best_effort: bool,
+
+    /// Run the diagnosis without printing anything and only report the result with the exit
+    /// code: 0 if everything is fine, 1 if warnings were emitted, 2 if an expected device is
+    /// missing or the diagnosis failed, 3 if libusb failed to enumerate the USB devices and 4
+    /// if only bootloader devices were found.
+    /// With --expect-uuid, only warnings about the expected devices and general warnings are
+    /// considered.
+    #[arg(long)]
+    check_only: bool,
+
+    /// Do not print anything except for errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Do not ask for confirmation before state-changing subcommands like reset
+    ///
+    /// This is required if stdin is not a terminal.
+    #[arg(short = 'y', long)]
+    assume_yes: bool,
+
+    /// Show additional details in the text output and log messages on stderr
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Also write the log messages to this file
+    ///
+    /// If the file cannot be opened, the diagnosis continues without it.
+    #[arg(long)]
+    log_file: Option<path::PathBuf>,
+
+    /// The output format
+    ///
+    /// By default, text is printed if stdout is a terminal and JSON otherwise, e. g. if the
+    /// output is piped.  Use --format text to force the text output.
+    #[arg(long, value_enum, default_value_t = Format::Auto)]
+    format: Format,
+
+    /// Print a single summary line instead, e. g. for a status bar
+    ///
+    /// To keep this fast, no applets other than admin are selected.  The admin queries still
+    /// run as their results are needed for the warnings.  Warnings with the info severity are
+    /// not counted.
+    #[arg(long, conflicts_with_all = ["format", "check_only"])]
+    oneline: bool,
+
+    /// Stop at the first reachable firmware device with a valid UUID and only print that
+    /// device
+    ///
+    /// The remaining smartcard readers are not probed.  If no such device is found, the exit
+    /// code is non-zero.
+    #[arg(
+        long,
+        conflicts_with_all = ["oneline", "check_only", "strict", "state_file", "also_json"]
+    )]
+    first_match: bool,
+
+    /// Also write the JSON output to this file, independent of --format
+    ///
+    /// The file is also written with --quiet and --check-only.
+    #[arg(long, value_name = "PATH")]
+    also_json: Option<path::PathBuf>,
+
+    /// Drop null fields and empty arrays from the JSON output
+    ///
+    /// By default, all fields are included and lists are always arrays, even if they are
+    /// empty.  With this option, such fields are omitted entirely, so consumers have to treat
+    /// a missing field like null or an empty array.  Empty objects are kept.
+    #[arg(long)]
+    omit_empty: bool,
+
+    /// Include all APDUs sent to the smartcard readers and their responses in the JSON output
+    #[arg(long)]
+    include_apdu_log: bool,
+
+    /// Write the USB enumeration results and all CCID exchanges to this file, e. g. for a
+    /// bug report
+    #[arg(long, value_name = "PATH", conflicts_with = "replay")]
+    record: Option<path::PathBuf>,
+
+    /// Replay a scan written with --record instead of accessing the devices
+    #[arg(long, value_name = "PATH")]
+    replay: Option<path::PathBuf>,
+
+    /// The format for UUIDs in the output
+    #[arg(long, value_enum, default_value_t = UuidFormat::Raw)]
+    uuid_format: UuidFormat,
+
+    /// Mask UUIDs in the output, keeping only the first and last four hex digits
+    ///
+    /// This is useful for sharing the output publicly.  The UUIDs in the --state-file are
+    /// not masked.
+    #[arg(long)]
+    redact: bool,
+
+    /// The USB backend used to enumerate firmware devices
+    #[arg(long, value_enum, default_value_t = UsbBackend::Auto)]
+    usb_backend: UsbBackend,
+
+    /// Include the USB descriptors of firmware devices found with libusb in the output
+    #[arg(long)]
+    dump_descriptors: bool,
+
+    /// Only include the descriptors of the USB interface with this number, e. g. for
+    /// firmware builds with extra interfaces
+    #[arg(long, value_name = "N", requires = "dump_descriptors")]
+    usb_interface: Option<u8>,
+
+    /// The set of applets to probe on firmware devices
+    #[arg(long, value_enum, default_value_t = AidSet::Builtin)]
+    aid_set: AidSet,
+
+    /// Probe the applet with this AID given as hex (can be repeated, requires --aid-set custom)
+    #[arg(
+        long = "select-aid",
+        value_name = "AID",
+        value_parser = parse_aid,
+        required_if_eq("aid_set", "custom")
+    )]
+    select_aids: Vec<Vec<u8>>,
+
+    /// Probe the known applets with these names in exactly this order, e. g.
+    /// openpgp,fido2,piv (replaces --aid-set)
+    ///
+    /// The admin applet is always selected first as it is required to query the UUID.
+    #[arg(
+        long,
+        value_name = "NAMES",
+        value_delimiter = ',',
+        value_parser = parse_applet_name,
+        conflicts_with_all = ["aid_set", "select_aids"]
+    )]
+    probe_order: Vec<Vec<u8>>,
+
+    /// Retry the UUID query of a firmware device this many times if it fails
+    #[arg(long, default_value_t = 2)]
+    uuid_retries: u32,
+
+    /// The delay before the first UUID query retry, doubled for every further retry
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "10ms")]
+    uuid_retry_delay: time::Duration,
+
+    /// Send this many test commands to firmware devices connected via NFC to rate the
+    /// connection quality (0 to disable)
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    nfc_quality_probes: u32,
+
+    /// How to release the card of a firmware reader after probing it
+    #[arg(long, value_enum, default_value_t = DisconnectMode::Reset)]
+    disconnect_mode: DisconnectMode,
+
+    /// Treat the smartcard reader with this name as a firmware reader (can be repeated)
+    #[arg(long = "force-firmware-reader", value_name = "NAME")]
+    force_firmware_readers: Vec<String>,
+
+    /// Also read this admin config key from firmware devices, e. g. a key that is not known
+    /// to this tool (can be repeated)
+    #[arg(long = "config-key", value_name = "KEY")]
+    config_keys: Vec<String>,
+
+    /// Repeatedly enumerate the devices for this duration (e. g. 10s or 500ms) and report the
+    /// throughput instead of running the diagnosis
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    bench: Option<time::Duration>,
+
+    /// Also probe the smartcard readers in each --bench iteration
+    #[arg(long, requires = "bench")]
+    bench_ccid: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Print the firmware version of a device
+    Version {
+        #[command(flatten)]
+        device: DeviceSelector,
+    },
+    /// Reset the card of a device at the reader level and print the new ATR
+    Reset {
+        #[command(flatten)]
+        device: DeviceSelector,
+        /// Power-cycle the card instead of a warm reset
+        #[arg(long)]
+        unpower: bool,
+    },
+    /// Check whether a device is ready for a firmware update
+    UpdateReady {
+        #[command(flatten)]
+        device: DeviceSelector,
+    },
+    /// Check the environment and suggest fixes for common problems
+    ///
+    /// The exit code is 0 if all checks passed, 1 if there are warnings and 2 if a check
+    /// failed.
+    Doctor,
+    /// Send a single command to the admin applet of a device and print the response data and
+    /// status word as hex
+    ///
+    /// The admin applet is selected automatically.  All values are hex, e. g. --ins 61.
+    AdminRaw {
+        #[command(flatten)]
+        device: DeviceSelector,
+        /// The instruction byte
+        #[arg(long, value_parser = parse_hex_byte)]
+        ins: u8,
+        #[arg(long, value_parser = parse_hex_byte, default_value = "00")]
+        p1: u8,
+        #[arg(long, value_parser = parse_hex_byte, default_value = "00")]
+        p2: u8,
+        /// The command data
+        // Fully qualified so that clap parses the data as a single value
+        #[arg(long, value_parser = parse_hex, default_value = "")]
+        data: ::std::vec::Vec<u8>,
+        /// The expected response length (00 for up to 256 bytes)
+        #[arg(long, value_parser = parse_hex_byte)]
+        le: Option<u8>,
+    },
+    /// List the known applets and their AIDs
+    ListAids,
+    /// Decode a UUID into its byte groups and describe what it probably is, without
+    /// accessing a device
+    DecodeUuid {
+        /// The UUID in the raw or the canonical format
+        uuid: Uuid,
+    },
+    /// Compare two saved --format json outputs of the device listing, e. g. before and after
+    /// a firmware update
+    ///
+    /// Reports the added and removed devices and the changed firmware versions and applets.
+    Diff {
+        old: path::PathBuf,
+        new: path::PathBuf,
+    },
+    /// Print the JSON Schema of the --format json output of the device listing
+    Schema,
+    /// Serve the JSON diagnosis over HTTP at GET /diagnose
+    ///
+    /// Every request runs a new diagnosis.  Requests are handled one at a time.
+    Serve {
+        /// The port to listen on
+        #[arg(long, default_value_t = 8000)]
+        port: u16,
+        /// The address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: std::net::IpAddr,
+    },
+}
+
+/// Selects the firmware device that a subcommand operates on.
+///
+/// If no device is selected and stdin is a terminal, the user is asked to pick one.
+#[derive(Clone, Copy, Debug, clap::Args)]
+#[group(required = false, multiple = false)]
+struct DeviceSelector {
+    /// The UUID of the device
+    #[arg(long)]
+    uuid: Option<Uuid>,
+    /// The index of the device in the firmware status list (starting at 1)
+    #[arg(long)]
+    index: Option<usize>,
+}
+
+impl DeviceSelector {
+    fn find(self, readers: &[FirmwareReader]) -> anyhow::Result<&FirmwareReader> {
+        match (self.uuid, self.index) {
+            (Some(uuid), _) => readers
+                .iter()
+                .find(|reader| reader.uuid == uuid)
+                .with_context(|| format!("No firmware device with the uuid {} found", uuid)),
+            (None, Some(index)) => index
+                .checked_sub(1)
+                .and_then(|i| readers.get(i))
+                .with_context(|| {
+                    format!(
+                        "Device index {} is out of range ({} firmware device(s) found)",
+                        index,
+                        readers.len()
+                    )
+                }),
+            (None, None) => {
+                anyhow::ensure!(
+                    io::stdin().is_terminal(),
+                    "No device selected.  Use --uuid or --index to select a device."
+                );
+                prompt_device(readers)
+            }
+        }
+    }
 }
 
-impl From<lpc55::bootloader::Bootloader> for Device {
-    fn from(bootloader: lpc55::bootloader::Bootloader) -> Self {
-        Self::Bootloader {
-            vid: bootloader.vid,
-            pid: bootloader.pid,
-            uuid: bootloader.uuid,
+/// Asks the user to pick one of the readers from a numbered list.
+fn prompt_device(readers: &[FirmwareReader]) -> anyhow::Result<&FirmwareReader> {
+    match readers {
+        [] => anyhow::bail!("No firmware device found"),
+        [reader] => return Ok(reader),
+        _ => {}
+    }
+    let stderr = io::stderr();
+    let mut out = stderr.lock();
+    writeln!(out, "Multiple firmware devices found:")?;
+    for (i, reader) in readers.iter().enumerate() {
+        writeln!(out, "[{}] {}", i + 1, reader)?;
+    }
+    loop {
+        write!(out, "Select a device [1-{}]: ", readers.len())?;
+        out.flush()?;
+        let mut line = String::new();
+        let n = io::stdin()
+            .read_line(&mut line)
+            .context("Failed to read the device selection")?;
+        anyhow::ensure!(n > 0, "No device selected");
+        match line.trim().parse::<usize>() {
+            Ok(index) if (1..=readers.len()).contains(&index) => return Ok(&readers[index - 1]),
+            _ => writeln!(out, "Invalid selection {:?}", line.trim())?,
         }
     }
 }
 
-impl fmt::Display for Device {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+/// The admin applet is always selected as it is required to query the UUID.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum AidSet {
+    /// admin (A00000084700000001) and provisioner (A00000084701000001)
+    Builtin,
+    /// All known applets: admin (A00000084700000001), provisioner (A00000084701000001),
+    /// fido2 (A0000006472F0001), openpgp (D27600012401), piv (A000000308),
+    /// secrets (A0000005272101) and ndef (D2760000850101)
+    Full,
+    /// admin and the applets given with --select-aid
+    Custom,
+}
+
+impl AidSet {
+    fn applets(&self, custom_aids: &[Vec<u8>]) -> Vec<Applet> {
         match self {
-            Self::Bootloader { vid, pid, uuid } => write!(
-                f,
-                "Bootloader {:04x}:{:04x} with uuid {:032x}",
-                vid, pid, uuid
-            ),
-            Self::Firmware { bus, address } => {
-                write!(f, "Firmware on bus {:03} device {:03}", bus, address)
+            Self::Builtin => [AID_ADMIN, AID_PROVISIONER]
+                .iter()
+                .map(|aid| Applet::new(aid))
+                .collect(),
+            Self::Full => KNOWN_APPLETS
+                .iter()
+                .map(|(_, aid)| Applet::new(aid))
+                .collect(),
+            Self::Custom => {
+                let mut applets = vec![Applet::new(AID_ADMIN)];
+                applets.extend(
+                    custom_aids
+                        .iter()
+                        .filter(|aid| aid.as_slice() != AID_ADMIN)
+                        .map(|aid| Applet::new(aid)),
+                );
+                applets
             }
         }
     }
 }
 
-#[derive(Debug, Default)]
-struct ReaderStatus {
-    firmware_readers: Vec<FirmwareReader>,
-    unsupported_readers: Vec<anyhow::Error>,
-    other_readers: Vec<ffi::CString>,
-}
-
-#[derive(Debug)]
-enum Reader {
-    Firmware(FirmwareReader),
-    Unsupported(anyhow::Error),
-    Other(ffi::CString),
+/// Returns the applets in the order selected with --probe-order or --aid-set.
+fn probe_applets(args: &Args) -> Vec<Applet> {
+    if args.probe_order.is_empty() {
+        return args.aid_set.applets(&args.select_aids);
+    }
+    let mut applets = Vec::new();
+    if !args
+        .probe_order
+        .iter()
+        .any(|aid| aid.as_slice() == AID_ADMIN)
+    {
+        applets.push(Applet::new(AID_ADMIN));
+    }
+    applets.extend(args.probe_order.iter().map(|aid| Applet::new(aid)));
+    applets
 }
 
 #[derive(Clone, Debug)]
-struct FirmwareReader {
-    uuid: u128,
-    provisioner: bool,
+struct Applet {
+    name: String,
+    aid: Vec<u8>,
 }
 
-impl fmt::Display for FirmwareReader {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "uuid {:032x}", self.uuid)?;
-        if self.provisioner {
-            write!(f, " with provisioner firmware")?;
+impl Applet {
+    fn new(aid: &[u8]) -> Self {
+        let name = KNOWN_APPLETS
+            .iter()
+            .find(|(_, known_aid)| *known_aid == aid)
+            .map(|(name, _)| *name)
+            .unwrap_or("custom");
+        Self {
+            name: name.to_owned(),
+            aid: aid.to_owned(),
         }
-        Ok(())
     }
 }
 
-fn find_bootloader_devices() -> Vec<Device> {
-    lpc55::bootloader::Bootloader::list()
-        .into_iter()
-        .map(From::from)
-        .collect()
+#[derive(Debug)]
+struct ProbeOptions {
+    progress: bool,
+    applets: Vec<Applet>,
+    forced_readers: Vec<ffi::CString>,
+    uuid_retries: u32,
+    uuid_retry_delay: time::Duration,
+    nfc_quality_probes: u32,
+    #[cfg(feature = "ccid")]
+    disconnect_mode: DisconnectMode,
+    config_keys: Vec<String>,
+    strict: bool,
+    compat_mode: bool,
+    first_match: bool,
 }
 
-fn find_firmware_devices() -> anyhow::Result<Vec<Device>> {
-    let mut firmware_devices = Vec::new();
-    let ctx = libusb::Context::new().context("Failed to establish libusb context")?;
-    let devices = ctx.devices().context("Failed to list USB devices")?;
-    for device in devices.iter() {
-        let desc = device
-            .device_descriptor()
-            .context("Failed to query device descriptor")?;
-        if desc.vendor_id() == VID_FIRMWARE && desc.product_id() == PID_FIRMWARE {
-            firmware_devices.push(Device::Firmware {
-                bus: device.bus_number(),
-                address: device.address(),
-            });
-        }
+impl ProbeOptions {
+    fn new(args: &Args, progress: bool, applets: Vec<Applet>) -> anyhow::Result<Self> {
+        let forced_readers = args
+            .force_firmware_readers
+            .iter()
+            .map(|name| ffi::CString::new(name.as_str()))
+            .collect::<Result<_, _>>()
+            .context("Reader names must not contain null bytes")?;
+        Ok(Self {
+            progress,
+            applets,
+            forced_readers,
+            uuid_retries: args.uuid_retries,
+            uuid_retry_delay: args.uuid_retry_delay,
+            nfc_quality_probes: args.nfc_quality_probes,
+            #[cfg(feature = "ccid")]
+            disconnect_mode: args.disconnect_mode,
+            config_keys: args.config_keys.clone(),
+            strict: args.strict,
+            compat_mode: args.compat_mode,
+            first_match: args.first_match,
+        })
     }
-    Ok(firmware_devices)
 }
 
-fn find_devices() -> anyhow::Result<Vec<Device>> {
-    let mut devices = Vec::new();
-    devices.extend(find_bootloader_devices());
-    devices.extend(find_firmware_devices()?);
-    Ok(devices)
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct AppletStatus {
+    /// The key in the applets object
+    #[serde(skip)]
+    name: String,
+    aid: String,
+    present: bool,
+    /// The applet is present but deactivated, e. g. after too many wrong PINs
+    blocked: bool,
+    /// The version of the applet, if it is reported by the applet
+    version: Option<String>,
+    pin_retries: Vec<PinRetries>,
+    /// Only available for the FIDO applet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fido_credentials: Option<FidoCredentials>,
+    /// Only available for the OpenPGP applet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    openpgp: Option<OpenPgpData>,
+    /// Only available for the secrets applet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secrets_credentials: Option<SecretsCredentials>,
 }
 
-fn get_reader_status() -> anyhow::Result<ReaderStatus> {
-    let mut reader_status = ReaderStatus::default();
-    for reader in get_readers()? {
-        match reader {
-            Reader::Firmware(reader) => reader_status.firmware_readers.push(reader),
-            Reader::Unsupported(error) => reader_status.unsupported_readers.push(error),
-            Reader::Other(reader) => reader_status.other_readers.push(reader),
+/// The number of credentials stored in the secrets applet.  The counts are null if the
+/// applet is protected with a PIN, as listing the credentials requires the PIN then.
+/// Credentials that are individually protected with a PIN are not listed.
+#[derive(Clone, Copy, Debug, serde::Serialize, schemars::JsonSchema)]
+struct SecretsCredentials {
+    totp: Option<usize>,
+    hotp: Option<usize>,
+    /// Other credential types, e. g. HMAC challenge-response or password safe entries
+    other: Option<usize>,
+}
+
+/// The public data objects of the OpenPGP applet that can be read without a PIN
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct OpenPgpData {
+    cardholder_name: Option<String>,
+    /// The URL of the public keys
+    url: Option<String>,
+    /// The fingerprints of the keys that are set
+    fingerprints: Vec<OpenPgpFingerprint>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct OpenPgpFingerprint {
+    key: &'static str,
+    fingerprint: String,
+}
+
+/// The result of the test commands sent to a device connected via NFC
+#[derive(Clone, Copy, Debug, serde::Serialize, schemars::JsonSchema)]
+struct NfcQualityReport {
+    probes: u32,
+    errors: u32,
+    quality: NfcQuality,
+}
+
+/// A coarse rating of the NFC connection based on the error rate of the test commands
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum NfcQuality {
+    /// All test commands succeeded
+    Good,
+    /// Up to a quarter of the test commands failed
+    Marginal,
+    /// More than a quarter of the test commands failed
+    Poor,
+}
+
+impl NfcQuality {
+    fn new(probes: u32, errors: u32) -> Self {
+        if errors == 0 {
+            Self::Good
+        } else if errors.saturating_mul(4) <= probes {
+            Self::Marginal
+        } else {
+            Self::Poor
         }
     }
-    Ok(reader_status)
 }
 
-fn ccid_transmit(
-    tx: &pcsc::Transaction<'_>,
-    ins: u8,
-    p1: u8,
-    p2: u8,
-    data: &[u8],
-    le: Option<u8>,
-) -> anyhow::Result<Vec<u8>> {
-    use std::convert::TryFrom as _;
+impl fmt::Display for NfcQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Good => "good",
+            Self::Marginal => "marginal, try to reposition the device",
+            Self::Poor => "poor, try to reposition the device",
+        };
+        f.write_str(s)
+    }
+}
 
-    let mut request = vec![
-        0x00, // Class
-        ins,  // Ins
-        p1,   // P1
-        p2,   // P2
-    ];
+/// The result of the health check of the random number generator
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum RngSelfTest {
+    Passed,
+    /// The random data is too short, constant or repeated
+    Failed,
+    /// The firmware does not support the random number command or it could not be queried
+    NotSupported,
+}
 
-    if !data.is_empty() {
-        // Lc
-        request.push(u8::try_from(data.len()).context("AID too long")?);
-        // Data
-        request.extend_from_slice(data);
-    }
-    if let Some(le) = le {
-        // Le
-        request.push(le);
+impl fmt::Display for RngSelfTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Passed => "passed",
+            Self::Failed => "FAILED",
+            Self::NotSupported => "not supported",
+        };
+        f.write_str(s)
     }
+}
 
-    let response_len = le
-        .map(|le| match le {
-            0 => usize::from(u8::MAX) + 1,
-            _ => usize::from(le),
-        })
-        .unwrap_or_default()
-        + 2;
-    let mut response = vec![0; response_len];
+/// A value read with the admin get config command
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct ConfigValue {
+    key: String,
+    /// The description of the key, or null if it is not known to this tool
+    description: Option<&'static str>,
+    value: String,
+}
 
-    let n = tx
-        .transmit(&request, &mut response)
-        .context("Failed to transmit data to smartcard")?
-        .len();
-    response.truncate(n);
+/// The usage of the FIDO credential storage
+#[derive(Clone, Copy, Debug, serde::Serialize, schemars::JsonSchema)]
+struct FidoCredentials {
+    /// The number of existing discoverable credentials.  Credential management always
+    /// requires the PIN, so this is skipped and never available.
+    existing: Option<u64>,
+    /// The number of discoverable credentials that can still be created, if reported by
+    /// the authenticator
+    remaining: Option<u64>,
+}
 
-    let sw2 = response.pop().context("CCID response too short")?;
-    let sw1 = response.pop().context("CCID response too short")?;
-    if (sw1, sw2) == (0x90, 0x00) {
-        Ok(response)
-    } else {
-        Err(anyhow::anyhow!(
+/// The number of firmware devices that have an applet
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct AppletCount {
+    name: String,
+    aid: String,
+    present: usize,
+}
+
+/// The number of remaining attempts for a PIN of an applet
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct PinRetries {
+    pin: &'static str,
+    remaining: u8,
+}
+
+/// The result of selecting an applet
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AppletState {
+    Present,
+    Blocked,
+    Absent,
+}
+
+/// A CCID command that was answered with a status word other than 9000
+#[derive(Debug)]
+struct CcidStatusError {
+    sw1: u8,
+    sw2: u8,
+}
+
+impl fmt::Display for CcidStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
             "CCID command failed with status code {:X}{:X}",
-            sw1,
-            sw2
-        ))
+            self.sw1, self.sw2
+        )
     }
 }
 
-fn ccid_select(tx: &pcsc::Transaction<'_>, aid: &[u8]) -> anyhow::Result<()> {
-    ccid_transmit(tx, 0xA4, 0x04, 0x00, aid, None)
-        .map(|_| {})
-        .with_context(|| format!("Failed to select AID {:x?}", aid))
+impl std::error::Error for CcidStatusError {}
+
+impl Format {
+    /// Replaces auto with the format for the current stdout.
+    fn resolve(self) -> Self {
+        match self {
+            Self::Auto if io::stdout().is_terminal() => Self::Text,
+            Self::Auto => Self::Json,
+            format => format,
+        }
+    }
+
+    /// Returns the format for the subcommands and --bench, or None if the format is only
+    /// supported for the device listing.  Must be called after resolve.
+    fn subcommand_format(self) -> Option<SubcommandFormat> {
+        match self {
+            Self::Text => Some(SubcommandFormat::Text),
+            Self::Json => Some(SubcommandFormat::Json),
+            Self::Auto | Self::Env | Self::Prometheus | Self::Csv | Self::Ndjson => None,
+        }
+    }
 }
 
-fn ccid_select2(tx: &pcsc::Transaction<'_>, aid: &[u8]) -> anyhow::Result<()> {
-    // Provisioner select returns 16 byte uuid
-    ccid_transmit(tx, 0xA4, 0x04, 0x00, aid, Some(16))
-        .map(|_| {})
-        .with_context(|| format!("Failed to select AID {:x?}", aid))
+/// The output formats supported by the subcommands and --bench
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SubcommandFormat {
+    Text,
+    Json,
 }
 
-fn admin_get_uuid(tx: &pcsc::Transaction<'_>) -> anyhow::Result<u128> {
-    ccid_transmit(tx, 0x62, 0x00, 0x00, &[], Some(16))
-        .context("Failed to query UUID")
-        .and_then(|response| {
-            use std::convert::TryInto as _;
-            response
-                .try_into()
-                .map_err(|_| anyhow::anyhow!("Expected 16 UUID bytes"))
-                .map(u128::from_be_bytes)
-        })
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum Format {
+    /// text if stdout is a terminal, json otherwise
+    Auto,
+    Text,
+    Json,
+    /// Shell variable assignments for the firmware devices, e. g. for
+    /// `eval "$(nk3-diagnose --format env)"` (only for the device listing)
+    Env,
+    /// Metrics in the Prometheus text exposition format, e. g. for the node exporter textfile
+    /// collector (only for the device listing)
+    Prometheus,
+    /// One row per physical device, e. g. for spreadsheets (only for the device listing)
+    Csv,
+    /// One JSON object per line for each physical device and a final summary line, e. g.
+    /// for log collectors (only for the device listing)
+    Ndjson,
 }
 
-fn get_firmware_reader(ctx: &pcsc::Context, reader: &ffi::CStr) -> anyhow::Result<FirmwareReader> {
-    let mut reader = ctx
-        .connect(reader, pcsc::ShareMode::Shared, pcsc::Protocols::T1)
-        .context("Failed to connect to smartcard reader")?;
-    let tx = reader
-        .transaction()
-        .context("Failed to start smartcard transaction")?;
-    ccid_select(&tx, AID_ADMIN).context("Failed to select admin application")?;
-    let uuid = admin_get_uuid(&tx).context("Failed to query UUID")?;
-    let provisioner = ccid_select2(&tx, AID_PROVISIONER).is_ok();
-    Ok(FirmwareReader { uuid, provisioner })
-}
-
-fn get_readers() -> anyhow::Result<Vec<Reader>> {
-    let ctx = pcsc::Context::establish(pcsc::Scope::System)
-        .context("Failed to establish pcsc context")?;
-    Ok(ctx
-        .list_readers_owned()
-        .context("Failed to list pcsc readers")?
-        .into_iter()
-        .map(|reader| {
-            if reader.as_bytes().starts_with(FIRMWARE_READER_NAME) {
-                match get_firmware_reader(&ctx, &reader) {
-                    Ok(reader) => Reader::Firmware(reader),
-                    Err(err) => Reader::Unsupported(err),
-                }
-            } else {
-                Reader::Other(reader)
-            }
-        })
-        .collect())
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+enum UuidFormat {
+    /// 32 hex digits without separators
+    #[default]
+    Raw,
+    /// 8-4-4-4-12 hex digits separated by dashes
+    Canonical,
 }
 
-fn main() -> anyhow::Result<()> {
-    let mut args = std::env::args();
-    let binary = args.next();
-    if args.count() > 0 {
-        let binary = binary
-            .as_ref()
-            .map(std::path::Path::new)
-            .and_then(std::path::Path::file_name)
-            .map(std::ffi::OsStr::to_string_lossy)
-            .unwrap_or_else(|| "nk3-diagnose".into());
-        eprintln!(
-            "{} {} -- display information about connected NK3 devices",
-            binary,
-            env!("CARGO_PKG_VERSION")
-        );
-        eprintln!("USAGE:");
-        eprintln!("  {}", binary);
-        eprintln!();
-        anyhow::bail!("Unexpected arguments");
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum DisconnectMode {
+    /// Leave the card powered, e. g. to keep the applet state for other clients
+    Leave,
+    /// Reset the card, the same as an implicit disconnect
+    Reset,
+    /// Power down the card
+    Unpower,
+}
+
+#[cfg(feature = "ccid")]
+impl From<DisconnectMode> for pcsc::Disposition {
+    fn from(mode: DisconnectMode) -> Self {
+        match mode {
+            DisconnectMode::Leave => Self::LeaveCard,
+            DisconnectMode::Reset => Self::ResetCard,
+            DisconnectMode::Unpower => Self::UnpowerCard,
+        }
     }
+}
 
-    let devices = find_devices()?;
-    anyhow::ensure!(!devices.is_empty(), "No supported devices found");
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+enum UsbBackend {
+    /// libusb, falling back to hidapi and then to sysfs (Linux only) if libusb does not find
+    /// any firmware devices
+    Auto,
+    /// libusb
+    Libusb,
+    /// hidapi (hidraw on Linux), e. g. for WSL where libusb cannot access the devices
+    Hidapi,
+    /// /sys/bus/usb/devices (Linux only), e. g. for containers without access to usbfs
+    Sysfs,
+}
 
-    println!("{} device(s) found:", devices.len());
-    for device in &devices {
-        println!("- {}", device);
+impl fmt::Display for UsbBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Auto => "auto",
+            Self::Libusb => "libusb",
+            Self::Hidapi => "hidapi",
+            Self::Sysfs => "sysfs",
+        };
+        f.write_str(name)
     }
+}
 
-    let reader_status = get_reader_status()?;
+/// The socket of the relay started for --pcsc-remote
+static RELAY_SOCKET: OnceLock<path::PathBuf> = OnceLock::new();
 
-    if !reader_status.firmware_readers.is_empty() {
-        println!();
-        println!("Firmware status:");
-        for reader in &reader_status.firmware_readers {
-            println!("- {}", reader);
+/// The result of the USB enumeration with libusb
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum LibusbStatus {
+    /// libusb was not used because another backend was selected
+    NotUsed,
+    /// libusb listed this number of USB devices (of any type)
+    Ok { device_count: usize },
+    /// libusb failed to enumerate the USB devices
+    Error { error: String },
+}
+
+/// The UUID format selected on the command line, used by all UUID output.
+static UUID_FORMAT: OnceLock<UuidFormat> = OnceLock::new();
+
+/// The log destinations, set from --verbose and --log-file
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+#[derive(Debug)]
+struct Logger {
+    stderr: bool,
+    file: Option<Mutex<fs::File>>,
+}
+
+fn log_message(args: fmt::Arguments<'_>) {
+    if let Some(logger) = LOGGER.get() {
+        if logger.stderr {
+            eprintln!("{}", args);
+        }
+        if let Some(file) = &logger.file {
+            let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+            // Logging must not abort the diagnosis
+            let _ = writeln!(file, "{}", args);
         }
     }
+}
 
-    if !reader_status.unsupported_readers.is_empty() {
-        println!();
-        println!("Firmware errors:");
-        for error in &reader_status.unsupported_readers {
-            println!("- {}", error);
-        }
+/// The APDU log, only enabled with --include-apdu-log
+static APDU_LOG: OnceLock<Mutex<ApduLog>> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct ApduLog {
+    /// The reader that is currently probed
+    reader: String,
+    entries: Vec<ApduLogEntry>,
+}
+
+/// A command sent to a reader and its response
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct ApduLogEntry {
+    reader: String,
+    request_hex: String,
+    response_hex: String,
+    sw: String,
+}
+
+fn apdu_log() -> Option<std::sync::MutexGuard<'static, ApduLog>> {
+    // A panic during a probe must not disable the log for the other readers
+    APDU_LOG
+        .get()
+        .map(|log| log.lock().unwrap_or_else(|err| err.into_inner()))
+}
+
+fn apdu_log_set_reader(name: &ffi::CStr) {
+    if let Some(mut log) = apdu_log() {
+        log.reader = name.to_string_lossy().into_owned();
     }
+}
 
-    println!();
-    let firmware_device_count = devices
-        .iter()
-        .filter(|device| matches!(device, Device::Firmware { bus: _, address: _ }))
-        .count();
-    if firmware_device_count > reader_status.firmware_readers.len() {
-        println!("Warning: Could not connect to one or more firmware devices.  Check that the updated Info.plist file is installed.");
+fn apdu_log_record(request: &[u8], response: &[u8]) {
+    if let Some(mut log) = apdu_log() {
+        let (data, sw) = response.split_at(response.len().saturating_sub(2));
+        let entry = ApduLogEntry {
+            reader: log.reader.clone(),
+            request_hex: format_hex(request),
+            response_hex: format_hex(data),
+            sw: format_hex(sw),
+        };
+        log.entries.push(entry);
     }
-    if firmware_device_count > 1 {
-        println!("Warning: Multiple firmware devices connected.  solo2 currently only supports accessing a single device.");
+}
+
+/// The scan that is recorded, only enabled with --record
+static RECORDING: OnceLock<Mutex<Recording>> = OnceLock::new();
+
+/// The USB enumeration results and CCID exchanges of a scan, written with --record and
+/// read with --replay
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+struct Recording {
+    usb: Option<UsbDevices>,
+    /// The error if the readers could not be listed
+    ccid_error: Option<String>,
+    /// The names of the readers listed by pcsc as hex
+    reader_names: Vec<String>,
+    /// The probed readers in the probe order
+    readers: Vec<RecordedReader>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct RecordedReader {
+    /// The reader name as hex
+    name: String,
+    connect_error: Option<String>,
+    /// The connection failed because no card is present
+    #[serde(default)]
+    no_card: bool,
+    max_apdu_data_size: Option<u32>,
+    driver: Option<ReaderDriver>,
+    /// The card is connected via NFC
+    #[serde(default)]
+    contactless: bool,
+    exchanges: Vec<RecordedExchange>,
+}
+
+/// A request and its response including the status word as hex, or the transmit error
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct RecordedExchange {
+    request: String,
+    response: Option<String>,
+    error: Option<String>,
+}
+
+fn recording() -> Option<std::sync::MutexGuard<'static, Recording>> {
+    RECORDING
+        .get()
+        .map(|recording| recording.lock().unwrap_or_else(|err| err.into_inner()))
+}
+
+#[cfg(feature = "ccid")]
+fn recording_set_reader_names(names: &[ffi::CString]) {
+    if let Some(mut recording) = recording() {
+        recording.reader_names = names
+            .iter()
+            .map(|name| format_hex(name.to_bytes()))
+            .collect();
     }
-    if !reader_status.other_readers.is_empty() {
-        println!("Warning: Found unsupported smartcard readers.  Please disconnect these readers before using solo2:");
-        for name in reader_status.other_readers {
-            println!("- {}", name.to_string_lossy());
-        }
+}
+
+#[cfg(feature = "ccid")]
+fn recording_add_reader(name: &ffi::CStr) {
+    if let Some(mut recording) = recording() {
+        recording.readers.push(RecordedReader {
+            name: format_hex(name.to_bytes()),
+            connect_error: None,
+            no_card: false,
+            max_apdu_data_size: None,
+            driver: None,
+            contactless: false,
+            exchanges: Vec::new(),
+        });
+    }
+}
+
+#[cfg(feature = "ccid")]
+fn recording_set_connect_error(error: &anyhow::Error, no_card: bool) {
+    if let Some(reader) = recording()
+        .as_mut()
+        .and_then(|recording| recording.readers.last_mut())
+    {
+        reader.connect_error = Some(format!("{:#}", error));
+        reader.no_card = no_card;
     }
+}
 
-    Ok(())
+#[cfg(feature = "ccid")]
+fn recording_set_reader_info(
+    max_apdu_data_size: Option<u32>,
+    driver: &ReaderDriver,
+    contactless: bool,
+) {
+    if let Some(reader) = recording()
+        .as_mut()
+        .and_then(|recording| recording.readers.last_mut())
+    {
+        reader.max_apdu_data_size = max_apdu_data_size;
+        reader.driver = Some(driver.clone());
+        reader.contactless = contactless;
+    }
+}
+
+fn recording_add_exchange(request: &[u8], response: &anyhow::Result<Vec<u8>>) {
+    if let Some(reader) = recording()
+        .as_mut()
+        .and_then(|recording| recording.readers.last_mut())
+    {
+        reader.exchanges.push(RecordedExchange {
+            request: format_hex(request),
+            response: response.as_ref().ok().map(|response| format_hex(response)),
+            error: response.as_ref().err().map(|err| format!("{:#}", err)),
+        });
+    }
+}
+
+fn read_recording(path: &path::Path) -> anyhow::Result<Recording> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read recording {}", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse recording {}", path.display()))
+}
+
+fn write_recording(path: &path::Path) -> anyhow::Result<()> {
+    let recording = match recording() {
+        Some(recording) => recording,
+        None => return Ok(()),
+    };
+    let content =
+        serde_json::to_string_pretty(&*recording).context("Failed to serialize recording")?;
+    fs::write(path, content + "\n")
+        .with_context(|| format!("Failed to write recording {}", path.display()))
+}
+
+/// Whether UUIDs are masked in the output, set from --redact
+static REDACT_UUIDS: OnceLock<bool> = OnceLock::new();
+
+impl Uuid {
+    fn format(self, redact: bool) -> String {
+        let s = match UUID_FORMAT.get().copied().unwrap_or_default() {
+            UuidFormat::Raw => format!("{:032x}", self.0),
+            UuidFormat::Canonical => format!(
+                "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+                self.0 >> 96,
+                (self.0 >> 80) & 0xffff,
+                (self.0 >> 64) & 0xffff,
+                (self.0 >> 48) & 0xffff,
+                self.0 & 0xffff_ffff_ffff
+            ),
+        };
+        if !redact {
+            return s;
+        }
+        // Separators are kept so that the masked UUID still has the selected format
+        let mut digit = 0;
+        s.chars()
+            .map(|c| {
+                if !c.is_ascii_hexdigit() {
+                    return c;
+                }
+                digit += 1;
+                if digit <= 4 || digit > 28 {
+                    c
+                } else {
+                    '*'
+                }
+            })
+            .collect()
+    }
+
+    /// Returns true for the sentinel values all zeros and all 0xFF that indicate an
+    /// uninitialized or corrupted device identity.
+    fn is_unset(self) -> bool {
+        self.0 == 0 || self.0 == u128::MAX
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+struct Uuid(u128);
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redact = REDACT_UUIDS.get().copied().unwrap_or_default();
+        f.write_str(&self.format(redact))
+    }
+}
+
+impl str::FromStr for Uuid {
+    type Err = anyhow::Error;
+
+    /// Accepts the raw and the canonical format, optionally with a 0x prefix.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let trimmed = s.trim();
+        let hex = trimmed
+            .strip_prefix("0x")
+            .or_else(|| trimmed.strip_prefix("0X"))
+            .unwrap_or(trimmed);
+        let groups: Vec<_> = hex.split('-').map(str::len).collect();
+        let valid = hex.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+            && (groups == [32] || groups == [8, 4, 4, 4, 12]);
+        anyhow::ensure!(
+            valid,
+            "Invalid UUID {:?}: expected 32 hex digits, optionally in the 8-4-4-4-12 format, \
+             e. g. 0123456789abcdef0123456789abcdef or 01234567-89ab-cdef-0123-456789abcdef",
+            s
+        );
+        u128::from_str_radix(&hex.replace('-', ""), 16)
+            .map(Self)
+            .context("Failed to parse UUID")
+    }
+}
+
+/// A firmware version as encoded by the admin version command
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+struct Version {
+    major: u32,
+    minor: u32,
+    patch: u32,
+}
+
+impl Version {
+    const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    fn known_version(&self) -> Option<&'static KnownVersion> {
+        KNOWN_VERSIONS
+            .iter()
+            .find(|known| (known.min..=known.max).contains(self))
+    }
+
+    fn status(&self) -> VersionStatus {
+        self.known_version()
+            .map(KnownVersion::status)
+            .unwrap_or(VersionStatus::Unknown)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum VersionStatus {
+    /// The latest release
+    Latest,
+    /// An older release that is still supported
+    Supported,
+    /// A release that is no longer supported
+    Eol,
+    /// A release with a known issue
+    KnownBuggy,
+    /// A version that is not in the table of known versions
+    Unknown,
+}
+
+impl fmt::Display for VersionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Latest => "latest",
+            Self::Supported => "supported",
+            Self::Eol => "end of life",
+            Self::KnownBuggy => "known issue",
+            Self::Unknown => "unknown version",
+        };
+        f.write_str(s)
+    }
+}
+
+/// An entry in KNOWN_BOOTLOADERS
+#[derive(Debug)]
+struct KnownBootloader {
+    version: &'static str,
+    min_firmware: Version,
+    max_firmware: Version,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum Compatibility {
+    Compatible,
+    Incompatible,
+    /// The bootloader version is not in the table of known bootloaders
+    Unknown,
+}
+
+impl fmt::Display for Compatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Compatible => "compatible",
+            Self::Incompatible => "incompatible",
+            Self::Unknown => "unknown compatibility",
+        };
+        f.write_str(s)
+    }
+}
+
+fn check_compatibility(bootloader: &str, firmware: Version) -> Compatibility {
+    match KNOWN_BOOTLOADERS
+        .iter()
+        .find(|known| known.version == bootloader)
+    {
+        Some(known) if (known.min_firmware..=known.max_firmware).contains(&firmware) => {
+            Compatibility::Compatible
+        }
+        Some(_) => Compatibility::Incompatible,
+        None => Compatibility::Unknown,
+    }
+}
+
+/// An entry in KNOWN_VERSIONS for a range of versions
+#[derive(Debug)]
+struct KnownVersion {
+    min: Version,
+    max: Version,
+    status: VersionStatus,
+    /// The issue and the recommended version for known buggy versions
+    issue: Option<(&'static str, Version)>,
+}
+
+impl KnownVersion {
+    fn status(&self) -> VersionStatus {
+        match self.issue {
+            Some(_) => VersionStatus::KnownBuggy,
+            None => self.status,
+        }
+    }
+
+    /// Returns the warning for a device running a version of this entry, if any.
+    fn warning(&self, uuid: Uuid, reader: String, version: Version) -> Option<Warning> {
+        let status = self.status();
+        matches!(status, VersionStatus::Eol | VersionStatus::KnownBuggy).then(|| {
+            Warning::FirmwareVersion {
+                uuid,
+                reader,
+                version,
+                status,
+                issue: self.issue,
+            }
+        })
+    }
+}
+
+impl From<u32> for Version {
+    fn from(version: u32) -> Self {
+        Self {
+            major: version >> 22,
+            minor: (version >> 6) & 0xffff,
+            patch: version & 0x3f,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl serde::Serialize for Version {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl serde::Serialize for Uuid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl schemars::JsonSchema for Version {
+    fn schema_name() -> String {
+        "Version".to_owned()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+impl schemars::JsonSchema for Uuid {
+    fn schema_name() -> String {
+        "Uuid".to_owned()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Uuid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Accept both the raw and the canonical format as the output format can be changed
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Device {
+    Bootloader {
+        vid: u16,
+        pid: u16,
+        uuid: Uuid,
+        /// The version of the bootloader, if it could be queried
+        version: Option<String>,
+        path: String,
+        serial: Option<String>,
+        manufacturer: Option<String>,
+        product: Option<String>,
+        /// The memory properties reported by the bootloader, null if none could be queried
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        memory: Option<BootloaderMemory>,
+    },
+    /// A device that is present but in a recovery mode and cannot be diagnosed further
+    Recovery {
+        vid: u16,
+        pid: u16,
+        description: String,
+        bus: Option<u8>,
+        address: Option<u8>,
+        path: Option<String>,
+    },
+    /// Firmware devices found with libusb have a bus and address, those found with hidapi
+    /// have a path.
+    Firmware {
+        bus: Option<u8>,
+        address: Option<u8>,
+        path: Option<String>,
+        /// The BCD-encoded device release number from the USB device descriptor
+        bcd_device: u16,
+        /// Only read with libusb
+        #[serde(flatten)]
+        strings: UsbStrings,
+        /// Why the string descriptors could not be read
+        #[serde(skip_serializing_if = "Option::is_none")]
+        strings_error: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        descriptors: Option<UsbDescriptors>,
+        /// Why the descriptors requested with --dump-descriptors could not be read
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptors_error: Option<String>,
+        /// Whether the active configuration has a CCID interface, only checked with libusb
+        ccid_interface: Option<bool>,
+    },
+}
+
+/// The flash and RAM configuration reported by the bootloader.  Every field is unavailable if
+/// the bootloader does not support the corresponding property.
+#[derive(
+    Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+struct BootloaderMemory {
+    flash_start_address: Option<usize>,
+    flash_size: Option<usize>,
+    flash_page_size: Option<usize>,
+    flash_sector_size: Option<usize>,
+    ram_start_address: Option<usize>,
+    ram_size: Option<usize>,
+    /// The flash regions reserved by the bootloader as start and end address
+    #[serde(default)]
+    reserved_regions: Vec<(usize, usize)>,
+}
+
+impl Device {
+    /// Returns true for firmware devices that are expected to have a smartcard reader.
+    fn expects_reader(&self) -> bool {
+        match self {
+            Self::Firmware { ccid_interface, .. } => *ccid_interface != Some(false),
+            Self::Bootloader { .. } | Self::Recovery { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bootloader {
+                vid,
+                pid,
+                uuid,
+                version,
+                path,
+                serial,
+                manufacturer,
+                product,
+                ..
+            } => {
+                write!(f, "Bootloader {:04x}:{:04x} with uuid {}", vid, pid, uuid)?;
+                let unknown = "unknown";
+                write!(
+                    f,
+                    " (version {}, manufacturer {}, product {}, serial {}, path {})",
+                    version.as_deref().unwrap_or(unknown),
+                    manufacturer.as_deref().unwrap_or(unknown),
+                    product.as_deref().unwrap_or(unknown),
+                    serial.as_deref().unwrap_or(unknown),
+                    path
+                )
+            }
+            Self::Recovery {
+                vid,
+                pid,
+                description,
+                bus,
+                address,
+                path,
+            } => {
+                write!(
+                    f,
+                    "Recovery device {:04x}:{:04x} ({})",
+                    vid, pid, description
+                )?;
+                if let (Some(bus), Some(address)) = (bus, address) {
+                    write!(f, " on bus {:03} device {:03}", bus, address)?;
+                }
+                if let Some(path) = path {
+                    write!(f, " at path {}", path)?;
+                }
+                Ok(())
+            }
+            Self::Firmware {
+                bus,
+                address,
+                path,
+                bcd_device,
+                strings,
+                strings_error,
+                ..
+            } => {
+                write!(f, "Firmware")?;
+                if let (Some(bus), Some(address)) = (bus, address) {
+                    write!(f, " on bus {:03} device {:03}", bus, address)?;
+                }
+                if let Some(path) = path {
+                    write!(f, " at path {}", path)?;
+                }
+                write!(
+                    f,
+                    " (bcdDevice {:x}.{:02x}",
+                    bcd_device >> 8,
+                    bcd_device & 0xff
+                )?;
+                if let Some(error) = strings_error {
+                    write!(f, ", {}", error)?;
+                } else if strings != &UsbStrings::default() {
+                    let unknown = "unknown";
+                    write!(
+                        f,
+                        ", manufacturer {}, product {}, serial {}",
+                        strings.manufacturer.as_deref().unwrap_or(unknown),
+                        strings.product.as_deref().unwrap_or(unknown),
+                        strings.serial.as_deref().unwrap_or(unknown)
+                    )?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum DeviceMode {
+    Bootloader,
+    Recovery,
+    Firmware,
+}
+
+impl fmt::Display for DeviceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bootloader => "bootloader",
+            Self::Recovery => "recovery",
+            Self::Firmware => "firmware",
+        }
+        .fmt(f)
+    }
+}
+
+/// A physical device with all USB devices and smartcard readers that belong to it
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct LogicalDevice {
+    /// Only unknown for firmware USB devices that could not be matched with a reader
+    uuid: Option<Uuid>,
+    mode: DeviceMode,
+    /// Indices into the device list
+    devices: Vec<usize>,
+    /// Indices into the firmware reader list
+    readers: Vec<usize>,
+    /// Only set if both the bootloader and the firmware version are known, e. g. if the
+    /// device rebooted during the scan
+    versions: Option<DeviceVersions>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct DeviceVersions {
+    bootloader: String,
+    firmware: Version,
+    compatibility: Compatibility,
+}
+
+/// The string descriptors of a USB device
+#[derive(
+    Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+struct UsbStrings {
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial: Option<String>,
+}
+
+/// The parsed USB descriptors of a device, see --dump-descriptors
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+struct UsbDescriptors {
+    usb_version: String,
+    class_code: u8,
+    sub_class_code: u8,
+    protocol_code: u8,
+    max_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    device_version: String,
+    manufacturer_string_index: Option<u8>,
+    product_string_index: Option<u8>,
+    serial_number_string_index: Option<u8>,
+    configurations: Vec<UsbConfiguration>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+struct UsbConfiguration {
+    number: u8,
+    max_power: u16,
+    self_powered: bool,
+    remote_wakeup: bool,
+    interfaces: Vec<UsbInterface>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+struct UsbInterface {
+    number: u8,
+    setting_number: u8,
+    class_code: u8,
+    sub_class_code: u8,
+    protocol_code: u8,
+    endpoints: Vec<UsbEndpoint>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+struct UsbEndpoint {
+    address: u8,
+    direction: String,
+    transfer_type: String,
+    sync_type: String,
+    usage_type: String,
+    max_packet_size: u16,
+    interval: u8,
+}
+
+#[derive(Debug, Default, serde::Serialize, schemars::JsonSchema)]
+struct ReaderStatus {
+    firmware_readers: Vec<FirmwareReader>,
+    /// Readers that could not be connected
+    connect_failed_readers: Vec<UnsupportedReader>,
+    /// Readers that could be connected, but the communication with the device failed
+    protocol_failed_readers: Vec<UnsupportedReader>,
+    /// Readers of devices that do not have the admin applet, e. g. a different firmware
+    incompatible_readers: Vec<UnsupportedReader>,
+    /// Readers whose device was removed during the scan
+    removed_readers: Vec<UnsupportedReader>,
+    /// Readers that could not be connected and do not have a matching USB device
+    stale_readers: Vec<UnsupportedReader>,
+    /// Readers with the firmware reader name without a card, e. g. NFC readers without a
+    /// device on them
+    empty_readers: Vec<EmptyReader>,
+    #[serde(serialize_with = "serialize_reader_names")]
+    #[schemars(with = "Vec<ReaderName<'static>>")]
+    other_readers: Vec<ffi::CString>,
+}
+
+impl ReaderStatus {
+    /// Moves readers that could not be connected to the stale readers if there is no USB
+    /// device left that could belong to them, i. e. if all firmware devices found via USB
+    /// already have a working reader.
+    ///
+    /// The USB results are only trusted if libusb found at least one firmware device and
+    /// could check all of them for a CCID interface.  Otherwise, e. g. without USB
+    /// permissions or with the hidapi or sysfs backend, a device with a busy or stuck reader
+    /// might be missing from the USB results.
+    fn classify_stale_readers(&mut self, devices: &[Device], usb_backend: UsbBackend) {
+        let firmware_devices: Vec<_> = devices
+            .iter()
+            .filter(|device| matches!(device, Device::Firmware { .. }))
+            .collect();
+        let trusted = usb_backend == UsbBackend::Libusb
+            && !firmware_devices.is_empty()
+            && firmware_devices.iter().all(|device| {
+                matches!(
+                    device,
+                    Device::Firmware {
+                        ccid_interface: Some(_),
+                        ..
+                    }
+                )
+            });
+        let reader_device_count = devices
+            .iter()
+            .filter(|device| device.expects_reader())
+            .count();
+        if !trusted || reader_device_count > self.firmware_readers.len() {
+            return;
+        }
+        self.stale_readers = std::mem::take(&mut self.connect_failed_readers);
+    }
+
+    /// Sorts the readers independently of the pcsc enumeration order: firmware readers by
+    /// UUID, the other readers by name and the unsupported readers by name and error.
+    fn sort(&mut self) {
+        self.firmware_readers
+            .sort_by(|a, b| a.uuid.cmp(&b.uuid).then_with(|| a.name.cmp(&b.name)));
+        for readers in [
+            &mut self.connect_failed_readers,
+            &mut self.protocol_failed_readers,
+            &mut self.incompatible_readers,
+            &mut self.stale_readers,
+            &mut self.removed_readers,
+        ] {
+            readers
+                .sort_by_cached_key(|reader| (reader.name.clone(), format!("{:#}", reader.error)));
+        }
+        self.empty_readers.sort_by(|a, b| a.name.cmp(&b.name));
+        self.other_readers.sort();
+    }
+
+    fn push_unsupported(&mut self, reader: UnsupportedReader) {
+        match reader.failure {
+            ReaderFailure::ConnectFailed => self.connect_failed_readers.push(reader),
+            ReaderFailure::ProtocolFailed => self.protocol_failed_readers.push(reader),
+            ReaderFailure::Incompatible => self.incompatible_readers.push(reader),
+            ReaderFailure::Removed => self.removed_readers.push(reader),
+        }
+    }
+
+    /// Returns the readers with the firmware reader name that could not be probed, without
+    /// the stale readers.
+    fn unsupported_readers(&self) -> impl Iterator<Item = &UnsupportedReader> {
+        self.connect_failed_readers
+            .iter()
+            .chain(&self.protocol_failed_readers)
+            .chain(&self.incompatible_readers)
+    }
+
+    fn state(&self) -> ReaderState {
+        if !self.firmware_readers.is_empty()
+            || self.unsupported_readers().next().is_some()
+            || !self.removed_readers.is_empty()
+            || !self.stale_readers.is_empty()
+        {
+            ReaderState::FirmwareFound
+        } else if !self.other_readers.is_empty() || !self.empty_readers.is_empty() {
+            ReaderState::OnlyOtherReaders
+        } else {
+            ReaderState::NoReaders
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum ReaderState {
+    /// pcsc does not report any smartcard readers
+    NoReaders,
+    /// There are smartcard readers, but none of them belongs to a firmware device
+    OnlyOtherReaders,
+    /// At least one smartcard reader belongs to a firmware device
+    FirmwareFound,
+}
+
+#[derive(Debug)]
+enum Reader {
+    Firmware(Box<FirmwareReader>),
+    Unsupported(UnsupportedReader),
+    /// A reader with the firmware reader name without a card
+    Empty(EmptyReader),
+    Other(ffi::CString),
+}
+
+/// A reader with the firmware reader name without a card.  Its properties are queried with a
+/// direct connection, which does not require a card.
+#[derive(Debug, serde::Serialize, schemars::JsonSchema)]
+struct EmptyReader {
+    #[serde(flatten, serialize_with = "serialize_reader_name")]
+    #[schemars(with = "ReaderName<'static>")]
+    name: ffi::CString,
+    /// Null if the reader does not support direct connections
+    driver: Option<ReaderDriver>,
+    max_apdu_data_size: Option<u32>,
+}
+
+/// A reader with the firmware reader name that could not be probed
+#[derive(Debug, serde::Serialize, schemars::JsonSchema)]
+struct UnsupportedReader {
+    #[serde(flatten, serialize_with = "serialize_reader_name")]
+    #[schemars(with = "ReaderName<'static>")]
+    name: ffi::CString,
+    #[serde(serialize_with = "serialize_error")]
+    #[schemars(with = "String")]
+    error: anyhow::Error,
+    #[serde(skip)]
+    failure: ReaderFailure,
+}
+
+/// The reason why a reader with the firmware reader name could not be probed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReaderFailure {
+    /// The connection to the reader could not be established
+    ConnectFailed,
+    /// The communication with the device failed
+    ProtocolFailed,
+    /// The device does not have the admin applet
+    Incompatible,
+    /// The device or the reader disappeared while it was probed
+    Removed,
+}
+
+/// Error context for pcsc errors that mean that the device was unplugged during the probe
+#[derive(Debug)]
+struct DeviceRemoved;
+
+impl fmt::Display for DeviceRemoved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Device removed during scan")
+    }
+}
+
+/// Returns true if the pcsc error means that the card or the reader is gone.
+#[cfg(feature = "ccid")]
+fn is_removal_error(err: pcsc::Error) -> bool {
+    matches!(
+        err,
+        pcsc::Error::RemovedCard | pcsc::Error::ReaderUnavailable | pcsc::Error::UnknownReader
+    )
+}
+
+/// Adds the DeviceRemoved context to pcsc errors that mean that the device is gone.
+#[cfg(feature = "ccid")]
+fn pcsc_error_context(err: pcsc::Error, context: &'static str) -> anyhow::Error {
+    let error = anyhow::Error::new(err).context(context);
+    if is_removal_error(err) {
+        error.context(DeviceRemoved)
+    } else {
+        error
+    }
+}
+
+/// Error context for an admin applet select that was answered with a status word
+#[derive(Debug)]
+struct IncompatibleDevice;
+
+impl fmt::Display for IncompatibleDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Failed to select admin application")
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, schemars::JsonSchema)]
+struct FirmwareReader {
+    #[serde(flatten, serialize_with = "serialize_reader_name")]
+    #[schemars(with = "ReaderName<'static>")]
+    name: ffi::CString,
+    uuid: Uuid,
+    version: Option<Version>,
+    version_status: Option<VersionStatus>,
+    /// The firmware does not support the version command, i. e. it is an old version
+    legacy: bool,
+    build_info: Option<String>,
+    provisioner: bool,
+    secure_boot: Option<bool>,
+    init_status: Option<u8>,
+    needs_factory_reset: bool,
+    rng_self_test: RngSelfTest,
+    /// The touch configuration values by key, or null if not supported by the firmware
+    touch_config: Option<BTreeMap<String, String>>,
+    /// The readable admin config values, or null if not supported by the firmware
+    config: Option<Vec<ConfigValue>>,
+    max_apdu_data_size: Option<u32>,
+    driver: ReaderDriver,
+    /// Whether the device is connected via NFC, or null if the card status is unavailable
+    contactless: Option<bool>,
+    /// Only available for devices connected via NFC
+    nfc_quality: Option<NfcQualityReport>,
+    /// The probed applets by name, in probing order
+    #[serde(serialize_with = "serialize_applets")]
+    #[schemars(with = "BTreeMap<String, AppletStatus>")]
+    applets: Vec<AppletStatus>,
+}
+
+/// The vendor information reported by the reader driver.  Every field is unavailable if
+/// the driver does not support the corresponding attribute.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+struct ReaderDriver {
+    vendor: Option<String>,
+    ifd_type: Option<String>,
+    /// The vendor-specific IFD version as hex
+    ifd_version: Option<String>,
+}
+
+impl fmt::Display for FirmwareReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "uuid {}", self.uuid)?;
+        if self.uuid.is_unset() {
+            write!(f, " (unset or corrupt)")?;
+        }
+        if let (Some(version), Some(status)) = (&self.version, &self.version_status) {
+            write!(f, ", firmware v{} ({})", version, status)?;
+        } else if self.legacy {
+            write!(f, ", firmware legacy (version unavailable)")?;
+        }
+        if self.provisioner {
+            write!(f, " with provisioner firmware")?;
+        }
+        let secure_boot = match self.secure_boot {
+            Some(true) => "enabled",
+            Some(false) => "disabled",
+            None => "unknown",
+        };
+        write!(f, ", secure boot {}", secure_boot)?;
+        let applets: Vec<_> = self
+            .applets
+            .iter()
+            .filter(|applet| applet.present)
+            .map(|applet| {
+                let mut s = applet.name.clone();
+                if let Some(version) = &applet.version {
+                    s.push_str(&format!(" v{}", version));
+                }
+                if applet.blocked {
+                    s.push_str(" (blocked)");
+                }
+                s
+            })
+            .collect();
+        write!(f, ", applets: {}", applets.join(", "))
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize, schemars::JsonSchema)]
+struct UuidCheck {
+    present: Vec<Uuid>,
+    missing: Vec<Uuid>,
+    unexpected: Vec<Uuid>,
+}
+
+impl UuidCheck {
+    fn new(expected: &BTreeSet<Uuid>, found: &BTreeSet<Uuid>) -> Self {
+        Self {
+            present: expected.intersection(found).copied().collect(),
+            missing: expected.difference(found).copied().collect(),
+            unexpected: found.difference(expected).copied().collect(),
+        }
+    }
+}
+
+/// The UUIDs seen in the last run, stored in the --state-file
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+struct State {
+    #[serde(serialize_with = "serialize_unredacted_uuids")]
+    uuids: BTreeSet<Uuid>,
+}
+
+/// The state file is read in later runs, so the UUIDs must not be masked.
+fn serialize_unredacted_uuids<S: serde::Serializer>(
+    uuids: &BTreeSet<Uuid>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(uuids.iter().map(|uuid| uuid.format(false)))
+}
+
+/// The changes compared to the --state-file
+#[derive(Debug, Default, serde::Serialize, schemars::JsonSchema)]
+struct StateDelta {
+    added: Vec<Uuid>,
+    removed: Vec<Uuid>,
+}
+
+impl StateDelta {
+    fn new(previous: &BTreeSet<Uuid>, current: &BTreeSet<Uuid>) -> Self {
+        Self {
+            added: current.difference(previous).copied().collect(),
+            removed: previous.difference(current).copied().collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize, schemars::JsonSchema)]
+enum WarningKind {
+    UnreachableDevice,
+    MultipleDevices,
+    UnsupportedReader,
+    DuplicateUuid,
+    UnsetUuid,
+    SecureBootDisabled,
+    NeedsFactoryReset,
+    ProvisionerFirmware,
+    BlockedApplet,
+    BlockedPin,
+    PcscUnavailable,
+    StaleReader,
+    FirmwareVersion,
+    LibusbError,
+    LibusbNoDevices,
+    RecoveryDevice,
+    NoCcidInterface,
+    StuckCcidInterface,
+    IncompatibleVersions,
+    MixedFirmwareVersions,
+    RngSelfTestFailed,
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    clap::ValueEnum,
+    serde::Serialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+enum Severity {
+    /// Notes that do not require any action, e. g. multiple connected devices
+    Info,
+    /// Problems that can affect some use cases
+    Warn,
+    /// Problems that prevent the diagnosis or the use of a device
+    Error,
+}
+
+#[derive(Debug)]
+enum Warning {
+    UnreachableDevice,
+    MultipleDevices,
+    UnsupportedReader {
+        reader: String,
+    },
+    DuplicateUuid {
+        uuid: Uuid,
+        reader: String,
+    },
+    UnsetUuid {
+        uuid: Uuid,
+        reader: String,
+    },
+    SecureBootDisabled {
+        uuid: Uuid,
+        reader: String,
+    },
+    NeedsFactoryReset {
+        uuid: Uuid,
+        reader: String,
+        init_status: u8,
+    },
+    ProvisionerFirmware {
+        uuid: Uuid,
+        reader: String,
+    },
+    BlockedApplet {
+        uuid: Uuid,
+        reader: String,
+        applet: String,
+    },
+    BlockedPin {
+        uuid: Uuid,
+        reader: String,
+        applet: String,
+        pin: &'static str,
+    },
+    PcscUnavailable {
+        error: String,
+    },
+    StaleReader {
+        reader: String,
+    },
+    FirmwareVersion {
+        uuid: Uuid,
+        reader: String,
+        version: Version,
+        status: VersionStatus,
+        issue: Option<(&'static str, Version)>,
+    },
+    LibusbError {
+        error: String,
+    },
+    LibusbNoDevices,
+    RecoveryDevice {
+        device: String,
+    },
+    NoCcidInterface {
+        device: String,
+    },
+    StuckCcidInterface {
+        reader: String,
+    },
+    IncompatibleVersions {
+        uuid: Uuid,
+        bootloader: String,
+        firmware: Version,
+    },
+    MixedFirmwareVersions {
+        /// The UUIDs of the firmware devices by version
+        versions: BTreeMap<Version, Vec<Uuid>>,
+    },
+    RngSelfTestFailed {
+        uuid: Uuid,
+        reader: String,
+    },
+}
+
+impl Warning {
+    fn kind(&self) -> WarningKind {
+        match self {
+            Self::UnreachableDevice => WarningKind::UnreachableDevice,
+            Self::MultipleDevices => WarningKind::MultipleDevices,
+            Self::UnsupportedReader { .. } => WarningKind::UnsupportedReader,
+            Self::DuplicateUuid { .. } => WarningKind::DuplicateUuid,
+            Self::UnsetUuid { .. } => WarningKind::UnsetUuid,
+            Self::SecureBootDisabled { .. } => WarningKind::SecureBootDisabled,
+            Self::NeedsFactoryReset { .. } => WarningKind::NeedsFactoryReset,
+            Self::ProvisionerFirmware { .. } => WarningKind::ProvisionerFirmware,
+            Self::BlockedApplet { .. } => WarningKind::BlockedApplet,
+            Self::BlockedPin { .. } => WarningKind::BlockedPin,
+            Self::PcscUnavailable { .. } => WarningKind::PcscUnavailable,
+            Self::StaleReader { .. } => WarningKind::StaleReader,
+            Self::FirmwareVersion { .. } => WarningKind::FirmwareVersion,
+            Self::LibusbError { .. } => WarningKind::LibusbError,
+            Self::LibusbNoDevices => WarningKind::LibusbNoDevices,
+            Self::RecoveryDevice { .. } => WarningKind::RecoveryDevice,
+            Self::NoCcidInterface { .. } => WarningKind::NoCcidInterface,
+            Self::StuckCcidInterface { .. } => WarningKind::StuckCcidInterface,
+            Self::IncompatibleVersions { .. } => WarningKind::IncompatibleVersions,
+            Self::MixedFirmwareVersions { .. } => WarningKind::MixedFirmwareVersions,
+            Self::RngSelfTestFailed { .. } => WarningKind::RngSelfTestFailed,
+        }
+    }
+
+    fn severity(&self) -> Severity {
+        match self {
+            Self::MultipleDevices
+            | Self::ProvisionerFirmware { .. }
+            | Self::StaleReader { .. }
+            | Self::NoCcidInterface { .. } => Severity::Info,
+            Self::UnsupportedReader { .. }
+            | Self::SecureBootDisabled { .. }
+            | Self::BlockedApplet { .. }
+            | Self::BlockedPin { .. }
+            | Self::PcscUnavailable { .. }
+            | Self::FirmwareVersion { .. }
+            | Self::LibusbNoDevices
+            | Self::RecoveryDevice { .. }
+            | Self::StuckCcidInterface { .. }
+            | Self::MixedFirmwareVersions { .. } => Severity::Warn,
+            Self::UnreachableDevice
+            | Self::DuplicateUuid { .. }
+            | Self::UnsetUuid { .. }
+            | Self::NeedsFactoryReset { .. }
+            | Self::LibusbError { .. }
+            | Self::IncompatibleVersions { .. }
+            | Self::RngSelfTestFailed { .. } => Severity::Error,
+        }
+    }
+
+    fn uuid(&self) -> Option<Uuid> {
+        match self {
+            Self::DuplicateUuid { uuid, .. }
+            | Self::UnsetUuid { uuid, .. }
+            | Self::SecureBootDisabled { uuid, .. }
+            | Self::NeedsFactoryReset { uuid, .. }
+            | Self::ProvisionerFirmware { uuid, .. }
+            | Self::BlockedApplet { uuid, .. }
+            | Self::BlockedPin { uuid, .. }
+            | Self::FirmwareVersion { uuid, .. }
+            | Self::IncompatibleVersions { uuid, .. }
+            | Self::RngSelfTestFailed { uuid, .. } => Some(*uuid),
+            Self::UnreachableDevice
+            | Self::MultipleDevices
+            | Self::UnsupportedReader { .. }
+            | Self::PcscUnavailable { .. }
+            | Self::StaleReader { .. }
+            | Self::LibusbError { .. }
+            | Self::LibusbNoDevices
+            | Self::RecoveryDevice { .. }
+            | Self::NoCcidInterface { .. }
+            | Self::StuckCcidInterface { .. }
+            | Self::MixedFirmwareVersions { .. } => None,
+        }
+    }
+
+    fn reader(&self) -> Option<&str> {
+        match self {
+            Self::UnsupportedReader { reader }
+            | Self::StaleReader { reader }
+            | Self::StuckCcidInterface { reader }
+            | Self::DuplicateUuid { reader, .. }
+            | Self::UnsetUuid { reader, .. }
+            | Self::SecureBootDisabled { reader, .. }
+            | Self::NeedsFactoryReset { reader, .. }
+            | Self::ProvisionerFirmware { reader, .. }
+            | Self::BlockedApplet { reader, .. }
+            | Self::BlockedPin { reader, .. }
+            | Self::FirmwareVersion { reader, .. }
+            | Self::RngSelfTestFailed { reader, .. } => Some(reader),
+            Self::UnreachableDevice
+            | Self::MultipleDevices
+            | Self::PcscUnavailable { .. }
+            | Self::LibusbError { .. }
+            | Self::LibusbNoDevices
+            | Self::RecoveryDevice { .. }
+            | Self::NoCcidInterface { .. }
+            | Self::IncompatibleVersions { .. }
+            | Self::MixedFirmwareVersions { .. } => None,
+        }
+    }
+
+    /// Returns the UUIDs of the firmware devices by version if the warning is about the
+    /// versions of multiple devices.
+    fn versions(&self) -> Option<BTreeMap<String, Vec<Uuid>>> {
+        match self {
+            Self::MixedFirmwareVersions { versions } => Some(
+                versions
+                    .iter()
+                    .map(|(version, uuids)| (version.to_string(), uuids.clone()))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnreachableDevice => write!(
+                f,
+                "Could not connect to one or more firmware devices.  Check that the updated Info.plist file is installed."
+            ),
+            Self::MultipleDevices => write!(
+                f,
+                "Multiple firmware devices connected.  solo2 currently only supports accessing a single device."
+            ),
+            Self::UnsupportedReader { reader } => write!(
+                f,
+                "Found unsupported smartcard reader {}.  Please disconnect this reader before using solo2.",
+                reader
+            ),
+            Self::DuplicateUuid { uuid, reader } => write!(
+                f,
+                "Smartcard reader {} reports the uuid {} that is also reported by another reader.  The device identities are not unique.",
+                reader, uuid
+            ),
+            Self::UnsetUuid { uuid, reader } => write!(
+                f,
+                "Smartcard reader {} reports the uuid {}, so the device UUID is unset or corrupt.  The device may need to be re-provisioned.",
+                reader, uuid
+            ),
+            Self::SecureBootDisabled { uuid, .. } => write!(
+                f,
+                "Secure boot is disabled on the device with the uuid {}.",
+                uuid
+            ),
+            Self::NeedsFactoryReset {
+                uuid, init_status, ..
+            } => write!(
+                f,
+                "The device with the uuid {} is in an inconsistent state (init status {:#04x}).  A factory reset is required.",
+                uuid, init_status
+            ),
+            Self::ProvisionerFirmware { uuid, .. } => write!(
+                f,
+                "The device with the uuid {} runs the provisioner firmware.  Such devices must not be handed out to users.",
+                uuid
+            ),
+            Self::BlockedApplet { uuid, applet, .. } => write!(
+                f,
+                "The {} applet on the device with the uuid {} is blocked.",
+                applet, uuid
+            ),
+            Self::BlockedPin {
+                uuid, applet, pin, ..
+            } => write!(
+                f,
+                "The {} {} on the device with the uuid {} is blocked (0 remaining attempts).",
+                applet, pin, uuid
+            ),
+            Self::PcscUnavailable { error } => write!(
+                f,
+                "{}.  Smartcard readers were not checked, only USB results are shown.",
+                error
+            ),
+            Self::FirmwareVersion {
+                uuid,
+                version,
+                status,
+                issue,
+                ..
+            } => {
+                write!(
+                    f,
+                    "The device with the uuid {} runs firmware v{} ({}).",
+                    uuid, version, status
+                )?;
+                match issue {
+                    Some((issue, recommended)) => write!(
+                        f,
+                        "  {}  Please update to v{} or later.",
+                        issue, recommended
+                    ),
+                    None => write!(f, "  Please update to the latest firmware version."),
+                }
+            }
+            Self::LibusbError { error } => write!(
+                f,
+                "libusb failed to enumerate the USB devices: {}.  The libusb backend or the USB permissions may be misconfigured.",
+                error
+            ),
+            Self::LibusbNoDevices => write!(
+                f,
+                "libusb does not list any USB devices although HID devices can be enumerated.  The libusb backend or the USB permissions may be misconfigured."
+            ),
+            Self::RecoveryDevice { device } => {
+                write!(f, "{} is in recovery mode.  {}", device, RECOVERY_HINT)
+            }
+            Self::IncompatibleVersions {
+                uuid,
+                bootloader,
+                firmware,
+            } => write!(
+                f,
+                "The firmware version {} of the device with the uuid {} is not compatible with its bootloader version {}.  The device might be in a half-updated state, try to repeat the update.",
+                firmware, uuid, bootloader
+            ),
+            Self::NoCcidInterface { device } => write!(
+                f,
+                "{} is present, but the CCID interface is not enabled in this firmware configuration.",
+                device
+            ),
+            Self::StuckCcidInterface { reader } => write!(
+                f,
+                "Smartcard reader {} could not be connected although a USB device with a CCID interface is present.  The interface might be stuck, try replugging or resetting the device.",
+                reader
+            ),
+            Self::StaleReader { reader } => write!(
+                f,
+                "Smartcard reader {} could not be connected and has no matching USB device.  It is probably a stale entry, try restarting pcscd.",
+                reader
+            ),
+            Self::MixedFirmwareVersions { versions } => {
+                let versions: Vec<_> = versions
+                    .iter()
+                    .map(|(version, uuids)| format!("v{} ({} device(s))", version, uuids.len()))
+                    .collect();
+                write!(
+                    f,
+                    "The firmware devices run different firmware versions: {}.  A batch of devices should normally be uniform.",
+                    versions.join(", ")
+                )
+            }
+            Self::RngSelfTestFailed { uuid, .. } => write!(
+                f,
+                "The random number generator of the device with the uuid {} failed the self-test.  Do not use the device for cryptographic operations and contact Nitrokey support.",
+                uuid
+            ),
+        }
+    }
+}
+
+/// The JSON representation of a warning
+#[derive(serde::Serialize, schemars::JsonSchema)]
+#[schemars(rename = "Warning")]
+struct WarningJson<'a> {
+    kind: WarningKind,
+    severity: Severity,
+    uuid: Option<Uuid>,
+    reader: Option<&'a str>,
+    /// The UUIDs of the firmware devices by version, only set for mixed firmware versions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    versions: Option<BTreeMap<String, Vec<Uuid>>>,
+    message: String,
+}
+
+impl serde::Serialize for Warning {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        WarningJson {
+            kind: self.kind(),
+            severity: self.severity(),
+            uuid: self.uuid(),
+            reader: self.reader(),
+            versions: self.versions(),
+            message: self.to_string(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl CheckStatus {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Pass => 0,
+            Self::Warn => 1,
+            Self::Fail => 2,
+        }
+    }
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Pass => "pass",
+            Self::Warn => "warn",
+            Self::Fail => "fail",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The enumeration throughput measured with --bench
+#[derive(Debug, serde::Serialize)]
+struct BenchResult {
+    iterations: usize,
+    scans_per_second: f64,
+    mean_ms: f64,
+    p50_ms: f64,
+    p99_ms: f64,
+}
+
+impl BenchResult {
+    fn new(elapsed: time::Duration, mut latencies: Vec<time::Duration>) -> Self {
+        latencies.sort();
+        let percentile = |p: f64| {
+            let i = ((latencies.len() - 1) as f64 * p).round() as usize;
+            duration_ms(latencies[i])
+        };
+        let total: time::Duration = latencies.iter().sum();
+        Self {
+            iterations: latencies.len(),
+            scans_per_second: latencies.len() as f64 / elapsed.as_secs_f64(),
+            mean_ms: duration_ms(total) / latencies.len() as f64,
+            p50_ms: percentile(0.5),
+            p99_ms: percentile(0.99),
+        }
+    }
+}
+
+fn duration_ms(duration: time::Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// The result of an environment check run by the doctor subcommand
+#[derive(Debug, serde::Serialize)]
+struct Check {
+    name: &'static str,
+    status: CheckStatus,
+    message: String,
+    remediation: Option<&'static str>,
+}
+
+impl Check {
+    fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Pass,
+            message: message.into(),
+            remediation: None,
+        }
+    }
+
+    fn warn(name: &'static str, message: impl Into<String>, remediation: &'static str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Warn,
+            message: message.into(),
+            remediation: Some(remediation),
+        }
+    }
+
+    fn fail(name: &'static str, message: impl Into<String>, remediation: &'static str) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Fail,
+            message: message.into(),
+            remediation: Some(remediation),
+        }
+    }
+}
+
+/// The result of the diagnosis.  All lists are serialized as JSON arrays, even if they
+/// contain zero or one elements.
+#[derive(Debug, serde::Serialize, schemars::JsonSchema)]
+struct Diagnosis {
+    /// The version of the output format, see the schema subcommand
+    schema_version: u32,
+    /// The time of the diagnosis in RFC 3339 format (UTC)
+    generated_at: String,
+    /// The hostname of the machine, if available
+    hostname: Option<String>,
+    environment: Environment,
+    /// The USB backend that was used to enumerate the firmware devices
+    usb_backend: UsbBackend,
+    libusb: LibusbStatus,
+    devices: Vec<Device>,
+    reader_state: ReaderState,
+    #[serde(flatten)]
+    reader_status: ReaderStatus,
+    applet_summary: Vec<AppletCount>,
+    uuid_check: Option<UuidCheck>,
+    state_delta: Option<StateDelta>,
+    /// The devices and readers grouped by physical device
+    logical_devices: Vec<LogicalDevice>,
+    #[schemars(with = "Vec<WarningJson<'static>>")]
+    warnings: Vec<Warning>,
+    /// Only included with --include-apdu-log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    apdu_log: Option<Vec<ApduLogEntry>>,
+}
+
+impl Diagnosis {
+    /// Returns true if a device was found via USB or via a firmware reader.  With
+    /// --pcsc-remote, the readers can belong to devices that are not connected locally.
+    fn has_devices(&self) -> bool {
+        !self.devices.is_empty() || !self.reader_status.firmware_readers.is_empty()
+    }
+}
+
+/// The host environment of the diagnosis, e. g. for bug reports
+#[derive(Debug, serde::Serialize, schemars::JsonSchema)]
+struct Environment {
+    os: &'static str,
+    arch: &'static str,
+    /// Whether the pcsc context could be established
+    pcsc_available: bool,
+}
+
+impl Environment {
+    fn new(pcsc_available: bool) -> Self {
+        Self {
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            pcsc_available,
+        }
+    }
+}
+
+/// A pcsc reader name in the JSON output.  `name` is decoded lossily, `raw_name` contains
+/// the exact bytes of the name as reported by pcsc as hex, e. g. for comparisons with the
+/// pcscd logs.
+#[derive(serde::Serialize, schemars::JsonSchema)]
+struct ReaderName<'a> {
+    name: borrow::Cow<'a, str>,
+    raw_name: String,
+}
+
+impl<'a> From<&'a ffi::CStr> for ReaderName<'a> {
+    fn from(name: &'a ffi::CStr) -> Self {
+        Self {
+            name: name.to_string_lossy(),
+            raw_name: format_hex(name.to_bytes()),
+        }
+    }
+}
+
+fn serialize_reader_name<S: serde::Serializer>(
+    name: &ffi::CString,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(&ReaderName::from(name.as_c_str()), serializer)
+}
+
+fn serialize_reader_names<S: serde::Serializer>(
+    names: &[ffi::CString],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(names.iter().map(|name| ReaderName::from(name.as_c_str())))
+}
+
+fn serialize_applets<S: serde::Serializer>(
+    applets: &[AppletStatus],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(applets.iter().map(|applet| (&applet.name, applet)))
+}
+
+fn serialize_error<S: serde::Serializer>(
+    error: &anyhow::Error,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:#}", error))
+}
+
+fn parse_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        s.len().is_multiple_of(2) && s.chars().all(|c| c.is_ascii_hexdigit()),
+        "Expected an even number of hex digits"
+    );
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).context("Failed to parse hex string"))
+        .collect()
+}
+
+fn parse_hex_byte(s: &str) -> anyhow::Result<u8> {
+    match parse_hex(s)?.as_slice() {
+        [byte] => Ok(*byte),
+        _ => anyhow::bail!("Expected a single byte (two hex digits)"),
+    }
+}
+
+fn parse_aid(s: &str) -> anyhow::Result<Vec<u8>> {
+    let aid = parse_hex(s)?;
+    anyhow::ensure!(
+        (5..=16).contains(&aid.len()),
+        "AID must consist of 5 to 16 bytes"
+    );
+    Ok(aid)
+}
+
+/// Returns the AID of the known applet with the given name.
+fn parse_applet_name(s: &str) -> anyhow::Result<Vec<u8>> {
+    KNOWN_APPLETS
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, aid)| aid.to_vec())
+        .with_context(|| {
+            let names: Vec<_> = KNOWN_APPLETS.iter().map(|(name, _)| *name).collect();
+            format!(
+                "Unknown applet {} (expected one of {})",
+                s,
+                names.join(", ")
+            )
+        })
+}
+
+fn parse_duration(s: &str) -> anyhow::Result<time::Duration> {
+    let (value, unit) = s
+        .find(|c: char| !c.is_ascii_digit())
+        .map(|i| s.split_at(i))
+        .unwrap_or((s, "s"));
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("Invalid duration {}", s))?;
+    let duration = match unit {
+        "ms" => time::Duration::from_millis(value),
+        "s" => time::Duration::from_secs(value),
+        "m" => time::Duration::from_secs(value * 60),
+        _ => anyhow::bail!("Invalid duration unit {} (expected ms, s or m)", unit),
+    };
+    anyhow::ensure!(!duration.is_zero(), "Duration must not be zero");
+    Ok(duration)
+}
+
+/// Normalizes a reader name for comparisons by trimming it, collapsing whitespace and
+/// converting it to lowercase.
+fn normalize_reader_name(name: &[u8]) -> String {
+    String::from_utf8_lossy(name)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Checks whether the normalized reader name starts with the normalized firmware reader name.
+fn is_firmware_reader_name(name: &ffi::CStr) -> bool {
+    normalize_reader_name(name.to_bytes()).starts_with(&normalize_reader_name(FIRMWARE_READER_NAME))
+}
+
+fn format_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn read_uuids_file(path: &path::Path) -> anyhow::Result<Vec<Uuid>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read UUIDs file {}", path.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            line.parse()
+                .with_context(|| format!("Invalid UUID in line {} of {}", i + 1, path.display()))
+        })
+        .collect()
+}
+
+fn summarize_applets(applets: &[Applet], readers: &[FirmwareReader]) -> Vec<AppletCount> {
+    applets
+        .iter()
+        .map(|applet| {
+            let aid = format_hex(&applet.aid);
+            let present = readers
+                .iter()
+                .filter(|reader| {
+                    reader
+                        .applets
+                        .iter()
+                        .any(|status| status.aid == aid && status.present)
+                })
+                .count();
+            AppletCount {
+                name: applet.name.clone(),
+                aid,
+                present,
+            }
+        })
+        .collect()
+}
+
+/// Reads the state file, treating a missing or corrupt file as empty.
+fn read_state_file(path: &path::Path) -> State {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_state_file(path: &path::Path, state: &State) -> anyhow::Result<()> {
+    let content = serde_json::to_string_pretty(state).context("Failed to serialize state")?;
+    fs::write(path, content + "\n")
+        .with_context(|| format!("Failed to write state file {}", path.display()))
+}
+
+fn find_duplicate_uuids(readers: &[FirmwareReader]) -> Vec<(Uuid, Vec<&ffi::CStr>)> {
+    let mut readers_by_uuid = BTreeMap::<_, Vec<_>>::new();
+    for reader in readers {
+        readers_by_uuid
+            .entry(reader.uuid)
+            .or_default()
+            .push(reader.name.as_c_str());
+    }
+    readers_by_uuid
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .collect()
+}
+
+/// Groups the USB devices and firmware readers by UUID.  The firmware USB devices do not
+/// report their UUID, so they can only be matched with a reader if there is exactly one
+/// firmware USB device and one firmware reader.  Otherwise they are listed separately.
+fn unify_devices(devices: &[Device], readers: &[FirmwareReader]) -> Vec<LogicalDevice> {
+    let mut devices_by_uuid = BTreeMap::<_, LogicalDevice>::new();
+    let mut recovery = Vec::new();
+    let mut unmatched = Vec::new();
+    for (i, device) in devices.iter().enumerate() {
+        match device {
+            Device::Bootloader { uuid, .. } => devices_by_uuid
+                .entry(*uuid)
+                .or_insert_with(|| LogicalDevice {
+                    uuid: Some(*uuid),
+                    mode: DeviceMode::Bootloader,
+                    devices: Vec::new(),
+                    readers: Vec::new(),
+                    versions: None,
+                })
+                .devices
+                .push(i),
+            Device::Recovery { .. } => recovery.push(LogicalDevice {
+                uuid: None,
+                mode: DeviceMode::Recovery,
+                devices: vec![i],
+                readers: Vec::new(),
+                versions: None,
+            }),
+            Device::Firmware { .. } => unmatched.push(i),
+        }
+    }
+    for (i, reader) in readers.iter().enumerate() {
+        devices_by_uuid
+            .entry(reader.uuid)
+            .or_insert_with(|| LogicalDevice {
+                uuid: Some(reader.uuid),
+                mode: DeviceMode::Firmware,
+                devices: Vec::new(),
+                readers: Vec::new(),
+                versions: None,
+            })
+            .readers
+            .push(i);
+    }
+    if let ([device], [reader]) = (unmatched.as_slice(), readers) {
+        if let Some(logical_device) = devices_by_uuid.get_mut(&reader.uuid) {
+            logical_device.devices.push(*device);
+            unmatched.clear();
+        }
+    }
+    for logical_device in devices_by_uuid.values_mut() {
+        let bootloader = logical_device
+            .devices
+            .iter()
+            .find_map(|i| match &devices[*i] {
+                Device::Bootloader { version, .. } => version.clone(),
+                Device::Recovery { .. } | Device::Firmware { .. } => None,
+            });
+        let firmware = logical_device
+            .readers
+            .iter()
+            .find_map(|i| readers[*i].version);
+        if let (Some(bootloader), Some(firmware)) = (bootloader, firmware) {
+            logical_device.versions = Some(DeviceVersions {
+                compatibility: check_compatibility(&bootloader, firmware),
+                bootloader,
+                firmware,
+            });
+        }
+    }
+    devices_by_uuid
+        .into_values()
+        .chain(recovery)
+        .chain(unmatched.into_iter().map(|i| LogicalDevice {
+            uuid: None,
+            mode: DeviceMode::Firmware,
+            devices: vec![i],
+            readers: Vec::new(),
+            versions: None,
+        }))
+        .collect()
+}
+
+fn find_bootloader_devices() -> anyhow::Result<Vec<Device>> {
+    use lpc55::bootloader::{property::GetProperties, protocol::Protocol};
+
+    // This mirrors lpc55::bootloader::Bootloader::list but keeps the HID device information.
+    let api = hidapi::HidApi::new().context("Failed to initialize hidapi")?;
+    let mut bootloader_devices = Vec::new();
+    for info in api.device_list() {
+        if info.manufacturer_string() != Some(BOOTLOADER_MANUFACTURER)
+            || info.product_string() != Some(BOOTLOADER_PRODUCT)
+        {
+            continue;
+        }
+        // A bootloader that does not respond is reported so that it does not go unnoticed
+        let recovery = Device::Recovery {
+            vid: info.vendor_id(),
+            pid: info.product_id(),
+            description: "unresponsive LPC55 bootloader".to_owned(),
+            bus: None,
+            address: None,
+            path: Some(info.path().to_string_lossy().into_owned()),
+        };
+        let protocol = match info.open_device(&api) {
+            Ok(device) => Protocol::new(device),
+            Err(_) => {
+                bootloader_devices.push(recovery);
+                continue;
+            }
+        };
+        let properties = GetProperties {
+            protocol: &protocol,
+        };
+        let uuid = match properties.device_uuid() {
+            Ok(uuid) => uuid,
+            Err(_) => {
+                bootloader_devices.push(recovery);
+                continue;
+            }
+        };
+        bootloader_devices.push(Device::Bootloader {
+            vid: info.vendor_id(),
+            pid: info.product_id(),
+            uuid: Uuid(uuid),
+            version: properties
+                .current_version()
+                .ok()
+                .map(|version| version.to_string()),
+            path: info.path().to_string_lossy().into_owned(),
+            serial: info.serial_number().map(ToOwned::to_owned),
+            manufacturer: info.manufacturer_string().map(ToOwned::to_owned),
+            product: info.product_string().map(ToOwned::to_owned),
+            memory: get_bootloader_memory(&properties),
+        });
+    }
+    Ok(bootloader_devices)
+}
+
+/// Queries the memory properties of a bootloader.  Returns None if none of them is
+/// available.
+fn get_bootloader_memory(
+    properties: &lpc55::bootloader::property::GetProperties<'_>,
+) -> Option<BootloaderMemory> {
+    let memory = BootloaderMemory {
+        flash_start_address: properties.flash_start_address().ok(),
+        flash_size: properties.flash_size().ok(),
+        flash_page_size: properties.flash_page_size().ok(),
+        flash_sector_size: properties.flash_sector_size().ok(),
+        ram_start_address: properties.ram_start_address().ok(),
+        ram_size: properties.ram_size().ok(),
+        reserved_regions: properties.reserved_regions().unwrap_or_default(),
+    };
+    (memory != BootloaderMemory::default()).then_some(memory)
+}
+
+fn format_usb_version(version: libusb::Version) -> String {
+    format!(
+        "{}.{}.{}",
+        version.major(),
+        version.minor(),
+        version.sub_minor()
+    )
+}
+
+/// Reads the string descriptors of a device.  This requires opening the device, which often
+/// fails, e. g. on Windows without the WinUSB driver.
+fn get_usb_strings(
+    device: &libusb::Device<'_>,
+    desc: &libusb::DeviceDescriptor,
+) -> anyhow::Result<UsbStrings> {
+    let timeout = time::Duration::from_millis(100);
+    let handle = device.open().context("Failed to open USB device")?;
+    let language = handle
+        .read_languages(timeout)
+        .context("Failed to read string descriptor languages")?
+        .into_iter()
+        .next()
+        .context("Device does not support any string descriptor language")?;
+    Ok(UsbStrings {
+        manufacturer: handle
+            .read_manufacturer_string(language, desc, timeout)
+            .ok(),
+        product: handle.read_product_string(language, desc, timeout).ok(),
+        serial: handle
+            .read_serial_number_string(language, desc, timeout)
+            .ok(),
+    })
+}
+
+fn get_usb_descriptors(
+    device: &libusb::Device<'_>,
+    desc: &libusb::DeviceDescriptor,
+    dump: DescriptorDump,
+) -> anyhow::Result<UsbDescriptors> {
+    let mut configurations = Vec::new();
+    for i in 0..desc.num_configurations() {
+        let config = device
+            .config_descriptor(i)
+            .with_context(|| format!("Failed to query configuration descriptor {}", i))?;
+        let interfaces = config
+            .interfaces()
+            .flat_map(|interface| interface.descriptors())
+            .filter(|interface| {
+                dump.interface
+                    .is_none_or(|number| number == interface.interface_number())
+            })
+            .map(|interface| UsbInterface {
+                number: interface.interface_number(),
+                setting_number: interface.setting_number(),
+                class_code: interface.class_code(),
+                sub_class_code: interface.sub_class_code(),
+                protocol_code: interface.protocol_code(),
+                endpoints: interface
+                    .endpoint_descriptors()
+                    .map(|endpoint| UsbEndpoint {
+                        address: endpoint.address(),
+                        direction: format!("{:?}", endpoint.direction()),
+                        transfer_type: format!("{:?}", endpoint.transfer_type()),
+                        sync_type: format!("{:?}", endpoint.sync_type()),
+                        usage_type: format!("{:?}", endpoint.usage_type()),
+                        max_packet_size: endpoint.max_packet_size(),
+                        interval: endpoint.interval(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        configurations.push(UsbConfiguration {
+            number: config.number(),
+            max_power: config.max_power(),
+            self_powered: config.self_powered(),
+            remote_wakeup: config.remote_wakeup(),
+            interfaces,
+        });
+    }
+    Ok(UsbDescriptors {
+        usb_version: format_usb_version(desc.usb_version()),
+        class_code: desc.class_code(),
+        sub_class_code: desc.sub_class_code(),
+        protocol_code: desc.protocol_code(),
+        max_packet_size: desc.max_packet_size(),
+        vendor_id: desc.vendor_id(),
+        product_id: desc.product_id(),
+        device_version: format_usb_version(desc.device_version()),
+        manufacturer_string_index: desc.manufacturer_string_index(),
+        product_string_index: desc.product_string_index(),
+        serial_number_string_index: desc.serial_number_string_index(),
+        configurations,
+    })
+}
+
+/// Checks whether the active configuration of the device has a CCID interface.  Firmware
+/// configurations without CCID support, e. g. FIDO-only builds, do not have a reader.
+fn has_ccid_interface(device: &libusb::Device<'_>) -> anyhow::Result<bool> {
+    let config = device
+        .active_config_descriptor()
+        .context("Failed to query active configuration descriptor")?;
+    let has_ccid_interface = config
+        .interfaces()
+        .flat_map(|interface| interface.descriptors())
+        .any(|interface| interface.class_code() == USB_CLASS_SMART_CARD);
+    Ok(has_ccid_interface)
+}
+
+/// The USB descriptors included in the output, selected with --dump-descriptors
+#[derive(Clone, Copy, Debug)]
+struct DescriptorDump {
+    /// Only include the interface with this number
+    interface: Option<u8>,
+}
+
+impl DescriptorDump {
+    fn new(args: &Args) -> Option<Self> {
+        args.dump_descriptors.then_some(Self {
+            interface: args.usb_interface,
+        })
+    }
+}
+
+/// Returns the total number of USB devices and the firmware devices.
+fn find_firmware_devices_libusb(
+    dump_descriptors: Option<DescriptorDump>,
+) -> anyhow::Result<(usize, Vec<Device>)> {
+    let mut firmware_devices = Vec::new();
+    let ctx = libusb::Context::new().context("Failed to establish libusb context")?;
+    let devices = ctx.devices().context("Failed to list USB devices")?;
+    for device in devices.iter() {
+        let desc = device
+            .device_descriptor()
+            .context("Failed to query device descriptor")?;
+        if desc.vendor_id() == VID_FIRMWARE && desc.product_id() == PID_FIRMWARE {
+            let version = desc.device_version();
+            // libusb decodes the BCD value, so it has to be encoded again
+            let bcd_device = (u16::from(version.major() / 10) << 12)
+                | (u16::from(version.major() % 10) << 8)
+                | (u16::from(version.minor()) << 4)
+                | u16::from(version.sub_minor());
+            let ccid_interface = match has_ccid_interface(&device) {
+                Ok(ccid_interface) => Some(ccid_interface),
+                Err(err) => {
+                    log!("Failed to check for a CCID interface: {:#}", err);
+                    None
+                }
+            };
+            // The descriptors are optional so that the device is still listed
+            let (descriptors, descriptors_error) =
+                match dump_descriptors.map(|dump| get_usb_descriptors(&device, &desc, dump)) {
+                    Some(Ok(descriptors)) => (Some(descriptors), None),
+                    Some(Err(err)) => {
+                        log!("Failed to read USB descriptors: {:#}", err);
+                        (None, Some(format!("{:#}", err)))
+                    }
+                    None => (None, None),
+                };
+            // The string descriptors are optional so that the device is still listed
+            let (strings, strings_error) = match get_usb_strings(&device, &desc) {
+                Ok(strings) => (strings, None),
+                Err(err) => {
+                    log!("Failed to read string descriptors: {:#}", err);
+                    let error = format!(
+                        "string descriptors unavailable{}: {:#}",
+                        STRING_DESCRIPTORS_HINT, err
+                    );
+                    (UsbStrings::default(), Some(error))
+                }
+            };
+            firmware_devices.push(Device::Firmware {
+                bus: Some(device.bus_number()),
+                address: Some(device.address()),
+                path: None,
+                bcd_device,
+                strings,
+                strings_error,
+                descriptors,
+                descriptors_error,
+                ccid_interface,
+            });
+        }
+    }
+    Ok((devices.len(), firmware_devices))
+}
+
+fn find_firmware_devices_hidapi() -> anyhow::Result<Vec<Device>> {
+    let api = hidapi::HidApi::new().context("Failed to initialize hidapi")?;
+    let mut devices: Vec<_> = api
+        .device_list()
+        .filter(|info| info.vendor_id() == VID_FIRMWARE && info.product_id() == PID_FIRMWARE)
+        .map(|info| {
+            (
+                info.path().to_string_lossy().into_owned(),
+                info.release_number(),
+            )
+        })
+        .collect();
+    devices.sort();
+    devices.dedup();
+    Ok(devices
+        .into_iter()
+        .map(|(path, bcd_device)| Device::Firmware {
+            bus: None,
+            address: None,
+            path: Some(path),
+            bcd_device,
+            strings: UsbStrings::default(),
+            strings_error: None,
+            descriptors: None,
+            descriptors_error: None,
+            ccid_interface: None,
+        })
+        .collect())
+}
+
+const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+/// Finds the firmware devices in the sysfs USB device directory.  The attributes are
+/// readable without access to usbfs, so this also works if libusb cannot open the devices.
+#[cfg(target_os = "linux")]
+fn find_firmware_devices_sysfs(root: &path::Path) -> anyhow::Result<Vec<Device>> {
+    let read = |path: &path::Path, name: &str| {
+        fs::read_to_string(path.join(name))
+            .ok()
+            .map(|value| value.trim().to_owned())
+    };
+    let read_hex = |path: &path::Path, name: &str| {
+        read(path, name).and_then(|value| u16::from_str_radix(&value, 16).ok())
+    };
+    let entries = fs::read_dir(root)
+        .with_context(|| format!("Failed to list USB devices in {}", root.display()))?;
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    paths.sort();
+    let mut firmware_devices = Vec::new();
+    for path in paths {
+        // Interfaces are listed next to the devices, but they do not have a vendor ID
+        if read_hex(&path, "idVendor") != Some(VID_FIRMWARE)
+            || read_hex(&path, "idProduct") != Some(PID_FIRMWARE)
+        {
+            continue;
+        }
+        // The interfaces of the active configuration are subdirectories named
+        // <device>:<configuration>.<interface>
+        let ccid_interface = fs::read_dir(&path).ok().map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().contains(':'))
+                .any(|entry| {
+                    read_hex(&entry.path(), "bInterfaceClass")
+                        == Some(u16::from(USB_CLASS_SMART_CARD))
+                })
+        });
+        firmware_devices.push(Device::Firmware {
+            bus: read(&path, "busnum").and_then(|value| value.parse().ok()),
+            address: read(&path, "devnum").and_then(|value| value.parse().ok()),
+            path: Some(path.to_string_lossy().into_owned()),
+            bcd_device: read_hex(&path, "bcdDevice").unwrap_or_default(),
+            strings: UsbStrings {
+                manufacturer: read(&path, "manufacturer"),
+                product: read(&path, "product"),
+                serial: read(&path, "serial"),
+            },
+            strings_error: None,
+            descriptors: None,
+            descriptors_error: None,
+            ccid_interface,
+        });
+    }
+    Ok(firmware_devices)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_firmware_devices_sysfs(_root: &path::Path) -> anyhow::Result<Vec<Device>> {
+    anyhow::bail!("The sysfs backend is only available on Linux")
+}
+
+/// The result of the USB devices enumeration
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct UsbDevices {
+    /// The backend that was used to find the firmware devices
+    backend: UsbBackend,
+    libusb: LibusbStatus,
+    devices: Vec<Device>,
+}
+
+/// Finds the firmware devices with the given backend.  Descriptors are only available with
+/// libusb.
+fn find_firmware_devices(
+    backend: UsbBackend,
+    dump_descriptors: Option<DescriptorDump>,
+) -> anyhow::Result<UsbDevices> {
+    match backend {
+        UsbBackend::Auto => {
+            // On some setups (e. g. WSL), libusb fails or does not see devices that are
+            // accessible via hidraw.
+            let libusb = match find_firmware_devices_libusb(dump_descriptors) {
+                Ok((device_count, devices)) if !devices.is_empty() => {
+                    return Ok(UsbDevices {
+                        backend: UsbBackend::Libusb,
+                        libusb: LibusbStatus::Ok { device_count },
+                        devices,
+                    })
+                }
+                Ok((device_count, _)) => {
+                    log!(
+                        "libusb lists {} USB device(s) but no firmware device, falling back to hidapi",
+                        device_count
+                    );
+                    LibusbStatus::Ok { device_count }
+                }
+                Err(err) => {
+                    log!("libusb failed, falling back to hidapi: {:#}", err);
+                    LibusbStatus::Error {
+                        error: format!("{:#}", err),
+                    }
+                }
+            };
+            let hidapi = find_firmware_devices_hidapi();
+            if cfg!(target_os = "linux")
+                && !hidapi.as_ref().is_ok_and(|devices| !devices.is_empty())
+            {
+                match find_firmware_devices_sysfs(path::Path::new(SYSFS_USB_DEVICES)) {
+                    Ok(devices) if !devices.is_empty() => {
+                        return Ok(UsbDevices {
+                            backend: UsbBackend::Sysfs,
+                            libusb,
+                            devices,
+                        })
+                    }
+                    Ok(_) => log!("sysfs does not list any firmware device either"),
+                    Err(err) => log!("sysfs fallback failed: {:#}", err),
+                }
+            }
+            match hidapi {
+                Ok(devices) => Ok(UsbDevices {
+                    backend: UsbBackend::Hidapi,
+                    libusb,
+                    devices,
+                }),
+                // The fallback failed, but the empty libusb result is still valid
+                Err(err) if matches!(libusb, LibusbStatus::Ok { .. }) => {
+                    log!("hidapi fallback failed: {:#}", err);
+                    Ok(UsbDevices {
+                        backend: UsbBackend::Libusb,
+                        libusb,
+                        devices: Vec::new(),
+                    })
+                }
+                Err(err) => Err(err),
+            }
+        }
+        UsbBackend::Libusb => {
+            let (device_count, devices) = find_firmware_devices_libusb(dump_descriptors)?;
+            Ok(UsbDevices {
+                backend: UsbBackend::Libusb,
+                libusb: LibusbStatus::Ok { device_count },
+                devices,
+            })
+        }
+        UsbBackend::Hidapi => Ok(UsbDevices {
+            backend: UsbBackend::Hidapi,
+            libusb: LibusbStatus::NotUsed,
+            devices: find_firmware_devices_hidapi()?,
+        }),
+        UsbBackend::Sysfs => Ok(UsbDevices {
+            backend: UsbBackend::Sysfs,
+            libusb: LibusbStatus::NotUsed,
+            devices: find_firmware_devices_sysfs(path::Path::new(SYSFS_USB_DEVICES))?,
+        }),
+    }
+}
+
+/// Finds the devices with the USB IDs in RECOVERY_DEVICES.  libusb errors are ignored as
+/// they are already reported by the firmware device enumeration.
+fn find_recovery_devices() -> Vec<Device> {
+    let mut recovery_devices = Vec::new();
+    let ctx = match libusb::Context::new() {
+        Ok(ctx) => ctx,
+        Err(_) => return recovery_devices,
+    };
+    let devices = match ctx.devices() {
+        Ok(devices) => devices,
+        Err(_) => return recovery_devices,
+    };
+    for device in devices.iter() {
+        let desc = match device.device_descriptor() {
+            Ok(desc) => desc,
+            Err(_) => continue,
+        };
+        let (vid, pid) = (desc.vendor_id(), desc.product_id());
+        if let Some((_, _, description)) = RECOVERY_DEVICES
+            .iter()
+            .find(|(known_vid, known_pid, _)| (*known_vid, *known_pid) == (vid, pid))
+        {
+            recovery_devices.push(Device::Recovery {
+                vid,
+                pid,
+                description: (*description).to_owned(),
+                bus: Some(device.bus_number()),
+                address: Some(device.address()),
+                path: None,
+            });
+        }
+    }
+    recovery_devices
+}
+
+fn find_devices(
+    backend: UsbBackend,
+    dump_descriptors: Option<DescriptorDump>,
+) -> anyhow::Result<UsbDevices> {
+    let mut devices = find_bootloader_devices()?;
+    devices.extend(find_recovery_devices());
+    let mut usb_devices = find_firmware_devices(backend, dump_descriptors)?;
+    log!(
+        "Found {} bootloader or recovery device(s) and {} firmware device(s) with {}",
+        devices.len(),
+        usb_devices.devices.len(),
+        usb_devices.backend
+    );
+    usb_devices.devices.splice(0..0, devices);
+    Ok(usb_devices)
+}
+
+/// Copies the data between the local pcsc-lite client and the remote pcscd.
+#[cfg(unix)]
+fn relay_pcsc_connection(local: std::os::unix::net::UnixStream, remote: std::net::TcpStream) {
+    use std::net::Shutdown;
+
+    let (mut local_reader, mut remote_reader) = match (local.try_clone(), remote.try_clone()) {
+        (Ok(local_reader), Ok(remote_reader)) => (local_reader, remote_reader),
+        _ => return,
+    };
+    let (mut local_writer, mut remote_writer) = (local, remote);
+    thread::spawn(move || {
+        let _ = io::copy(&mut local_reader, &mut remote_writer);
+        let _ = remote_writer.shutdown(Shutdown::Write);
+    });
+    thread::spawn(move || {
+        let _ = io::copy(&mut remote_reader, &mut local_writer);
+        let _ = local_writer.shutdown(Shutdown::Write);
+    });
+}
+
+/// Starts a relay from a local socket to a remote pcscd and returns the socket path.
+/// pcsc-lite uses the socket given in PCSCLITE_CSOCK_NAME, so the relay is transparent.
+#[cfg(unix)]
+fn start_pcsc_relay(remote: &str) -> anyhow::Result<path::PathBuf> {
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixListener;
+
+    // Connect once so that an unreachable daemon is reported directly instead of as a
+    // generic pcsc error
+    TcpStream::connect(remote)
+        .with_context(|| format!("Failed to connect to the remote pcscd at {}", remote))?;
+
+    let path = std::env::temp_dir().join(format!("nk3-diagnose-{}.comm", process::id()));
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to create pcsc relay socket {}", path.display()))?;
+    let remote = remote.to_owned();
+    thread::spawn(move || {
+        for local in listener.incoming().filter_map(Result::ok) {
+            if let Ok(stream) = TcpStream::connect(&remote) {
+                relay_pcsc_connection(local, stream);
+            }
+        }
+    });
+    Ok(path)
+}
+
+#[cfg(not(unix))]
+fn start_pcsc_relay(_remote: &str) -> anyhow::Result<path::PathBuf> {
+    anyhow::bail!("--pcsc-remote is only supported with pcsc-lite")
+}
+
+fn remove_relay_socket() {
+    if let Some(path) = RELAY_SOCKET.get() {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Exits with the given code after cleaning up the relay socket.
+fn exit(code: i32) -> ! {
+    remove_relay_socket();
+    process::exit(code)
+}
+
+#[cfg(feature = "ccid")]
+fn establish_pcsc_context() -> anyhow::Result<pcsc::Context> {
+    pcsc::Context::establish(pcsc::Scope::System).context("Failed to establish pcsc context")
+}
+
+#[cfg(feature = "ccid")]
+fn get_reader_status(ctx: &pcsc::Context, options: &ProbeOptions) -> anyhow::Result<ReaderStatus> {
+    get_readers(ctx, options).map(collect_reader_status)
+}
+
+fn collect_reader_status(readers: Vec<Reader>) -> ReaderStatus {
+    let mut reader_status = ReaderStatus::default();
+    for reader in readers {
+        match reader {
+            Reader::Firmware(reader) => reader_status.firmware_readers.push(*reader),
+            Reader::Unsupported(reader) => reader_status.push_unsupported(reader),
+            Reader::Empty(reader) => reader_status.empty_readers.push(reader),
+            Reader::Other(reader) => reader_status.other_readers.push(reader),
+        }
+    }
+    // The order determines the indices for --index
+    reader_status.sort();
+    reader_status
+}
+
+/// Probes the readers of a recording again, replaying the recorded exchanges.
+fn replay_reader_status(
+    recording: &Recording,
+    options: &ProbeOptions,
+) -> anyhow::Result<ReaderStatus> {
+    let names = recording
+        .reader_names
+        .iter()
+        .map(|name| {
+            let name = parse_hex(name)?;
+            ffi::CString::new(name).context("Reader names must not contain null bytes")
+        })
+        .collect::<anyhow::Result<_>>()
+        .context("Invalid reader name in recording")?;
+    let readers = probe_readers(names, options, |name| {
+        replay_firmware_reader(recording, name, options)
+    })?;
+    Ok(collect_reader_status(readers))
+}
+
+/// Placeholder for the pcsc context if CCID support is not compiled in
+#[cfg(not(feature = "ccid"))]
+enum PcscContext {}
+
+#[cfg(not(feature = "ccid"))]
+fn establish_pcsc_context() -> anyhow::Result<PcscContext> {
+    anyhow::bail!(CCID_UNSUPPORTED)
+}
+
+#[cfg(not(feature = "ccid"))]
+fn get_reader_status(ctx: &PcscContext, _options: &ProbeOptions) -> anyhow::Result<ReaderStatus> {
+    match *ctx {}
+}
+
+/// A connection to a card that APDUs can be exchanged over, e. g. a pcsc transaction or a
+/// replayed recording
+trait CardChannel {
+    /// Sends the request and returns the response including the status word.
+    fn exchange(&self, request: &[u8], max_response_len: usize) -> anyhow::Result<Vec<u8>>;
+
+    /// Returns the maximum APDU data size of the reader, if known.
+    fn max_apdu_data_size(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// A channel with the maximum APDU data size reported by the reader
+struct SizedChannel<'a> {
+    channel: &'a dyn CardChannel,
+    max_apdu_data_size: Option<u32>,
+}
+
+impl CardChannel for SizedChannel<'_> {
+    fn exchange(&self, request: &[u8], max_response_len: usize) -> anyhow::Result<Vec<u8>> {
+        self.channel.exchange(request, max_response_len)
+    }
+
+    fn max_apdu_data_size(&self) -> Option<u32> {
+        self.max_apdu_data_size
+    }
+}
+
+#[cfg(feature = "ccid")]
+impl CardChannel for pcsc::Transaction<'_> {
+    fn exchange(&self, request: &[u8], max_response_len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut response = vec![0; max_response_len];
+        let n = pcsc::Card::transmit(self, request, &mut response)
+            .map_err(|err| pcsc_error_context(err, "Failed to transmit data to smartcard"))?
+            .len();
+        response.truncate(n);
+        Ok(response)
+    }
+}
+
+/// Answers the requests with the exchanges of a recorded reader
+struct ReplayChannel {
+    exchanges: std::cell::RefCell<std::collections::VecDeque<RecordedExchange>>,
+}
+
+impl ReplayChannel {
+    fn new(exchanges: &[RecordedExchange]) -> Self {
+        Self {
+            exchanges: std::cell::RefCell::new(exchanges.iter().cloned().collect()),
+        }
+    }
+}
+
+impl CardChannel for ReplayChannel {
+    fn exchange(&self, request: &[u8], _max_response_len: usize) -> anyhow::Result<Vec<u8>> {
+        let request = format_hex(request);
+        let exchange = self
+            .exchanges
+            .borrow_mut()
+            .pop_front()
+            .with_context(|| format!("No recorded response for request {}", request))?;
+        anyhow::ensure!(
+            exchange.request == request,
+            "Request {} does not match the recorded request {}",
+            request,
+            exchange.request
+        );
+        match (exchange.response, exchange.error) {
+            (Some(response), _) => parse_hex(&response),
+            (None, error) => Err(anyhow::anyhow!(
+                error.unwrap_or_else(|| "Missing recorded response".to_owned())
+            )),
+        }
+    }
+}
+
+/// Sends a request and records the exchange for --record and --include-apdu-log.
+fn ccid_exchange(
+    tx: &dyn CardChannel,
+    request: &[u8],
+    max_response_len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let result = tx.exchange(request, max_response_len);
+    recording_add_exchange(request, &result);
+    let response = result?;
+    apdu_log_record(request, &response);
+    Ok(response)
+}
+
+fn ccid_transmit(
+    tx: &dyn CardChannel,
+    ins: u8,
+    p1: u8,
+    p2: u8,
+    data: &[u8],
+    le: Option<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    ccid_transmit_class(tx, 0x00, ins, p1, p2, data, le)
+}
+
+fn ccid_transmit_class(
+    tx: &dyn CardChannel,
+    cla: u8,
+    ins: u8,
+    p1: u8,
+    p2: u8,
+    data: &[u8],
+    le: Option<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    use std::convert::TryFrom as _;
+
+    let mut request = vec![
+        cla, // Class
+        ins, // Ins
+        p1,  // P1
+        p2,  // P2
+    ];
+
+    // If the reader supports extended length, the complete response is requested at once.
+    // Otherwise, it is fetched with GET RESPONSE below.
+    let extended_len = match (le, tx.max_apdu_data_size()) {
+        (Some(0), Some(size)) if size > u32::from(u8::MAX) + 1 => usize::try_from(size).ok(),
+        _ => None,
+    };
+    if let Some(extended_len) = extended_len {
+        if !data.is_empty() {
+            // Extended Lc
+            request.push(0x00);
+            request.extend_from_slice(
+                &u16::try_from(data.len())
+                    .context("AID too long")?
+                    .to_be_bytes(),
+            );
+            // Data
+            request.extend_from_slice(data);
+        } else {
+            request.push(0x00);
+        }
+        // Extended Le (0000 means the maximum length)
+        request.extend_from_slice(&[0x00, 0x00]);
+        let response_len = extended_len.min(usize::from(u16::MAX) + 1) + 2;
+        return ccid_transmit_response(tx, &request, response_len);
+    }
+
+    if !data.is_empty() {
+        // Lc
+        request.push(u8::try_from(data.len()).context("AID too long")?);
+        // Data
+        request.extend_from_slice(data);
+    }
+    if let Some(le) = le {
+        // Le
+        request.push(le);
+    }
+
+    let response_len = le
+        .map(|le| match le {
+            0 => usize::from(u8::MAX) + 1,
+            _ => usize::from(le),
+        })
+        .unwrap_or_default()
+        + 2;
+    ccid_transmit_response(tx, &request, response_len)
+}
+
+/// Sends the request and returns the response data.  Remaining response data is fetched with
+/// GET RESPONSE.
+fn ccid_transmit_response(
+    tx: &dyn CardChannel,
+    request: &[u8],
+    response_len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut response = ccid_exchange(tx, request, response_len)?;
+
+    let mut sw2 = response.pop().context("CCID response too short")?;
+    let mut sw1 = response.pop().context("CCID response too short")?;
+    // Readers without extended length support require the remaining response data to be
+    // fetched with GET RESPONSE.
+    while sw1 == 0x61 {
+        let expected = if sw2 == 0 {
+            usize::from(u8::MAX) + 1
+        } else {
+            usize::from(sw2)
+        };
+        let get_response = [0x00, 0xC0, 0x00, 0x00, sw2];
+        let mut chunk = ccid_exchange(tx, &get_response, expected + 2)?;
+        sw2 = chunk.pop().context("CCID response too short")?;
+        sw1 = chunk.pop().context("CCID response too short")?;
+        response.extend_from_slice(&chunk);
+    }
+    if (sw1, sw2) == (0x90, 0x00) {
+        Ok(response)
+    } else {
+        Err(CcidStatusError { sw1, sw2 }.into())
+    }
+}
+
+fn ccid_select(tx: &dyn CardChannel, aid: &[u8]) -> anyhow::Result<()> {
+    ccid_transmit(tx, 0xA4, 0x04, 0x00, aid, None)
+        .map(|_| {})
+        .with_context(|| format!("Failed to select AID {:x?}", aid))
+}
+
+fn ccid_select2(tx: &dyn CardChannel, aid: &[u8]) -> anyhow::Result<()> {
+    // Provisioner select returns 16 byte uuid
+    ccid_transmit(tx, 0xA4, 0x04, 0x00, aid, Some(16))
+        .map(|_| {})
+        .with_context(|| format!("Failed to select AID {:x?}", aid))
+}
+
+fn admin_get_uuid(tx: &dyn CardChannel) -> anyhow::Result<Uuid> {
+    ccid_transmit(tx, 0x62, 0x00, 0x00, &[], Some(16))
+        .context("Failed to query UUID")
+        .and_then(|response| {
+            use std::convert::TryInto as _;
+            response
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Expected 16 UUID bytes"))
+                .map(u128::from_be_bytes)
+                .map(Uuid)
+        })
+}
+
+/// Parses a list of TLV entries with one byte tag and length fields.
+fn parse_tlv(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut entries = Vec::new();
+    while let [tag, len, rest @ ..] = data {
+        let len = usize::from(*len);
+        if rest.len() < len {
+            break;
+        }
+        entries.push((*tag, &rest[..len]));
+        data = &rest[len..];
+    }
+    entries
+}
+
+/// Reads the header of a CBOR data item and returns the major type and the argument.
+/// Indefinite lengths are not supported.
+fn cbor_read_header(data: &mut &[u8]) -> Option<(u8, u64)> {
+    let (first, rest) = data.split_first()?;
+    let len = match first & 0x1F {
+        info @ 0..=23 => {
+            *data = rest;
+            return Some((first >> 5, u64::from(info)));
+        }
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => return None,
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let value = rest[..len]
+        .iter()
+        .fold(0, |value, byte| (value << 8) | u64::from(*byte));
+    *data = &rest[len..];
+    Some((first >> 5, value))
+}
+
+/// Skips a CBOR data item including all nested items.
+fn cbor_skip(data: &mut &[u8], depth: usize) -> Option<()> {
+    use std::convert::TryFrom as _;
+
+    let depth = depth.checked_sub(1)?;
+    let (major, value) = cbor_read_header(data)?;
+    match major {
+        // Byte and text strings
+        2 | 3 => {
+            let len = usize::try_from(value)
+                .ok()
+                .filter(|len| *len <= data.len())?;
+            *data = &data[len..];
+        }
+        // Arrays and maps
+        4 | 5 => {
+            let items = if major == 5 {
+                value.checked_mul(2)?
+            } else {
+                value
+            };
+            for _ in 0..items {
+                cbor_skip(data, depth)?;
+            }
+        }
+        // Tags
+        6 => cbor_skip(data, depth)?,
+        // Integers and simple values or floats
+        _ => {}
+    }
+    Some(())
+}
+
+/// Returns the unsigned integer value for an integer key in a CBOR map.
+fn cbor_map_get_uint(mut data: &[u8], key: u64) -> Option<u64> {
+    let (major, entries) = cbor_read_header(&mut data)?;
+    if major != 5 {
+        return None;
+    }
+    for _ in 0..entries {
+        let (key_major, key_value) = cbor_read_header(&mut data)?;
+        if (key_major, key_value) == (0, key) {
+            return match cbor_read_header(&mut data)? {
+                (0, value) => Some(value),
+                _ => None,
+            };
+        }
+        cbor_skip(&mut data, 16)?;
+    }
+    None
+}
+
+#[cfg(feature = "ccid")]
+fn reader_get_feature(card: &pcsc::Card, feature: u8) -> anyhow::Result<u32> {
+    let mut buffer = [0; 256];
+    let features = card
+        .control(
+            pcsc::ctl_code(CM_IOCTL_GET_FEATURE_REQUEST.into()),
+            &[],
+            &mut buffer,
+        )
+        .context("Failed to query reader features")?;
+    parse_tlv(features)
+        .into_iter()
+        .find(|(tag, _)| *tag == feature)
+        .and_then(|(_, value)| {
+            use std::convert::TryInto as _;
+            value.try_into().ok().map(u32::from_be_bytes)
+        })
+        .with_context(|| format!("Reader does not support feature {:#04x}", feature))
+}
+
+#[cfg(feature = "ccid")]
+fn reader_get_driver(card: &pcsc::Card) -> ReaderDriver {
+    let get_string = |attribute| {
+        card.get_attribute_owned(attribute).ok().and_then(|value| {
+            let value = String::from_utf8_lossy(&value);
+            let value = value.trim_end_matches('\0').trim();
+            (!value.is_empty()).then(|| value.to_owned())
+        })
+    };
+    ReaderDriver {
+        vendor: get_string(pcsc::Attribute::VendorName),
+        ifd_type: get_string(pcsc::Attribute::VendorIfdType),
+        ifd_version: card
+            .get_attribute_owned(pcsc::Attribute::VendorIfdVersion)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .map(|value| format_hex(&value)),
+    }
+}
+
+#[cfg(feature = "ccid")]
+fn reader_get_max_apdu_data_size(card: &pcsc::Card) -> anyhow::Result<u32> {
+    let control_code = reader_get_feature(card, FEATURE_GET_TLV_PROPERTIES)?;
+    let mut buffer = [0; 256];
+    let properties = card
+        .control(control_code.into(), &[], &mut buffer)
+        .context("Failed to query reader properties")?;
+    parse_tlv(properties)
+        .into_iter()
+        .find(|(tag, _)| *tag == PROPERTY_MAX_APDU_DATA_SIZE)
+        .map(|(_, value)| {
+            // Properties are little endian and may be shorter than four bytes
+            value
+                .iter()
+                .rev()
+                .fold(0, |size, byte| (size << 8) | u32::from(*byte))
+        })
+        .context("Reader does not report the maximum APDU data size")
+}
+
+fn admin_get_version(tx: &dyn CardChannel) -> anyhow::Result<Version> {
+    ccid_transmit(tx, 0x61, 0x00, 0x00, &[], Some(4))
+        .context("Failed to query firmware version")
+        .and_then(|response| {
+            use std::convert::TryInto as _;
+            response
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Expected 4 version bytes"))
+                .map(u32::from_be_bytes)
+                .map(Version::from)
+        })
+}
+
+/// Queries the build metadata of the firmware, i. e. the part after the `+` in the full
+/// version string (typically the commit or build timestamp of nightly builds).  Release
+/// builds do not have build metadata.
+fn admin_get_build_info(tx: &dyn CardChannel) -> anyhow::Result<Option<String>> {
+    // If the version command is called with the parameter 0x01, it returns the full version
+    // string instead of the encoded version.
+    let response = ccid_transmit(tx, 0x61, 0x00, 0x00, &[0x01], Some(0))
+        .context("Failed to query firmware version string")?;
+    let version = String::from_utf8(response).context("Firmware version is not valid UTF-8")?;
+    Ok(version
+        .split_once('+')
+        .map(|(_, build_info)| build_info.trim().to_owned()))
+}
+
+fn admin_get_secure_boot(tx: &dyn CardChannel) -> anyhow::Result<bool> {
+    // The locked command reports whether secure boot is enabled and the device is locked
+    ccid_transmit(tx, 0x63, 0x00, 0x00, &[], Some(1))
+        .context("Failed to query secure boot status")
+        .and_then(|response| match response.as_slice() {
+            [locked] => Ok(*locked != 0),
+            _ => Err(anyhow::anyhow!("Expected 1 secure boot status byte")),
+        })
+}
+
+/// Selects an applet and returns its state and the response to the select command.
+fn ccid_probe(tx: &dyn CardChannel, applet: &Applet) -> (AppletState, Vec<u8>) {
+    let result = if applet.aid == AID_PROVISIONER {
+        ccid_select2(tx, &applet.aid).map(|_| Vec::new())
+    } else {
+        // Some applets return file control information on select
+        ccid_transmit(tx, 0xA4, 0x04, 0x00, &applet.aid, Some(0))
+    };
+    match result {
+        Ok(response) => (AppletState::Present, response),
+        // 6283 (selected file deactivated) means that the applet exists but is blocked
+        Err(err) => match err.downcast_ref::<CcidStatusError>() {
+            Some(CcidStatusError {
+                sw1: 0x62,
+                sw2: 0x83,
+            }) => (AppletState::Blocked, Vec::new()),
+            _ => (AppletState::Absent, Vec::new()),
+        },
+    }
+}
+
+/// Reads the version of the OpenPGP specification implemented by the applet from the
+/// application identifier.
+fn openpgp_get_version(tx: &dyn CardChannel) -> anyhow::Result<String> {
+    let response = ccid_transmit(tx, 0xCA, 0x00, 0x4F, &[], Some(0))
+        .context("Failed to query OpenPGP application identifier")?;
+    match response.as_slice() {
+        [_, _, _, _, _, _, major, minor, ..] => Ok(format!("{}.{}", major, minor)),
+        _ => Err(anyhow::anyhow!("Expected 16 application identifier bytes")),
+    }
+}
+
+/// Reads a public OpenPGP data object with GET DATA and returns it as a string, or None if
+/// it is empty.
+fn openpgp_get_string(
+    tx: &dyn CardChannel,
+    tag: u16,
+    name: &str,
+) -> anyhow::Result<Option<String>> {
+    let [p1, p2] = tag.to_be_bytes();
+    let response = ccid_transmit(tx, 0xCA, p1, p2, &[], Some(0))
+        .with_context(|| format!("Failed to query OpenPGP {}", name))?;
+    let value = String::from_utf8(response)
+        .with_context(|| format!("OpenPGP {} is not valid UTF-8", name))?;
+    Ok((!value.is_empty()).then_some(value))
+}
+
+/// Converts a cardholder name from the OpenPGP format (surname, `<<`, given names, with `<`
+/// instead of spaces) into a readable name.
+fn openpgp_format_name(name: &str) -> String {
+    let name = match name.split_once("<<") {
+        Some((surname, given_names)) => format!("{} {}", given_names, surname),
+        None => name.to_owned(),
+    };
+    name.replace('<', " ").trim().to_owned()
+}
+
+/// Splits the OpenPGP fingerprint data object into the fingerprints of the signature,
+/// decryption and authentication key.  Keys without a fingerprint are not set.
+fn openpgp_parse_fingerprints(data: &[u8]) -> anyhow::Result<Vec<OpenPgpFingerprint>> {
+    if data.len() != 60 {
+        anyhow::bail!("Expected 60 fingerprint bytes");
+    }
+    Ok(["signature", "decryption", "authentication"]
+        .iter()
+        .zip(data.chunks(20))
+        .filter(|(_, fingerprint)| fingerprint.iter().any(|b| *b != 0))
+        .map(|(key, fingerprint)| OpenPgpFingerprint {
+            key,
+            fingerprint: format_hex(fingerprint),
+        })
+        .collect())
+}
+
+/// Reads the cardholder name, the public key URL and the key fingerprints.  These data
+/// objects are public, so no PIN is needed.
+fn openpgp_get_data(tx: &dyn CardChannel) -> anyhow::Result<OpenPgpData> {
+    let cardholder_name = openpgp_get_string(tx, 0x5B, "cardholder name")?;
+    let url = openpgp_get_string(tx, 0x5F50, "public key URL")?;
+    let fingerprints = ccid_transmit(tx, 0xCA, 0x00, 0xC5, &[], Some(0))
+        .context("Failed to query OpenPGP fingerprints")
+        .and_then(|response| openpgp_parse_fingerprints(&response))?;
+    Ok(OpenPgpData {
+        cardholder_name: cardholder_name.as_deref().map(openpgp_format_name),
+        url,
+        fingerprints,
+    })
+}
+
+/// Counts the credentials with the OATH list command, which does not require a touch.
+fn secrets_get_credentials(tx: &dyn CardChannel) -> anyhow::Result<SecretsCredentials> {
+    let response = match ccid_transmit(tx, 0xA1, 0x00, 0x00, &[], Some(0)) {
+        Ok(response) => response,
+        Err(err) => {
+            // 6982 (security status not satisfied) means that the applet is protected
+            if let Some(CcidStatusError {
+                sw1: 0x69,
+                sw2: 0x82,
+            }) = err.downcast_ref()
+            {
+                return Ok(SecretsCredentials {
+                    totp: None,
+                    hotp: None,
+                    other: None,
+                });
+            }
+            return Err(err.context("Failed to list secrets credentials"));
+        }
+    };
+    let (mut totp, mut hotp, mut other) = (0, 0, 0);
+    // Each entry has the tag 0x72 and starts with the kind in the upper nibble
+    for (_, entry) in parse_tlv(&response)
+        .into_iter()
+        .filter(|(tag, _)| *tag == 0x72)
+    {
+        match entry.first().map(|kind| kind & 0xF0) {
+            Some(0x10) => hotp += 1,
+            Some(0x20) => totp += 1,
+            _ => other += 1,
+        }
+    }
+    Ok(SecretsCredentials {
+        totp: Some(totp),
+        hotp: Some(hotp),
+        other: Some(other),
+    })
+}
+
+/// Parses the version of the secrets applet from its select response.
+fn secrets_parse_version(select_response: &[u8]) -> Option<String> {
+    parse_tlv(select_response)
+        .into_iter()
+        .find(|(tag, _)| *tag == 0x79)
+        .and_then(|(_, value)| match value {
+            [major, minor, patch] => Some(format!("{}.{}.{}", major, minor, patch)),
+            _ => None,
+        })
+}
+
+/// Reads the remaining discoverable credential capacity from the authenticatorGetInfo
+/// response.  The number of existing credentials can only be read with credential
+/// management, which requires the PIN, so it is skipped.
+fn fido_get_credentials(tx: &dyn CardChannel) -> anyhow::Result<FidoCredentials> {
+    // NFCCTAP_MSG with the authenticatorGetInfo command
+    let response = ccid_transmit_class(tx, 0x80, 0x10, 0x00, 0x00, &[0x04], Some(0))
+        .context("Failed to query FIDO authenticator info")?;
+    let info = match response.split_first() {
+        Some((0x00, info)) => info,
+        Some((status, _)) => {
+            anyhow::bail!("authenticatorGetInfo failed with status {:#04x}", status)
+        }
+        None => anyhow::bail!("Empty authenticatorGetInfo response"),
+    };
+    Ok(FidoCredentials {
+        existing: None,
+        // remainingDiscoverableCredentials, only reported by CTAP 2.1 authenticators
+        remaining: cbor_map_get_uint(info, 0x14),
+    })
+}
+
+/// Reads the retry counters of the user and admin PIN from the PW status bytes.  The reset
+/// code is skipped as its counter is also zero if no reset code is set.
+fn openpgp_get_pin_retries(tx: &dyn CardChannel) -> anyhow::Result<Vec<PinRetries>> {
+    let response = ccid_transmit(tx, 0xCA, 0x00, 0xC4, &[], Some(0))
+        .context("Failed to query OpenPGP PW status bytes")?;
+    match response.as_slice() {
+        [_, _, _, _, user, _, admin, ..] => Ok(vec![
+            PinRetries {
+                pin: "user PIN",
+                remaining: *user,
+            },
+            PinRetries {
+                pin: "admin PIN",
+                remaining: *admin,
+            },
+        ]),
+        _ => Err(anyhow::anyhow!("Expected 7 PW status bytes")),
+    }
+}
+
+/// Reads the retry counter of the PIV application PIN.  A VERIFY command without data only
+/// returns the counter and does not consume an attempt.
+fn piv_get_pin_retries(tx: &dyn CardChannel) -> anyhow::Result<Vec<PinRetries>> {
+    let err = match ccid_transmit(tx, 0x20, 0x00, 0x80, &[], None) {
+        // The PIN has already been verified, so the counter is not available
+        Ok(_) => return Ok(Vec::new()),
+        Err(err) => err,
+    };
+    let remaining = match err.downcast_ref::<CcidStatusError>() {
+        Some(CcidStatusError { sw1: 0x63, sw2 }) if sw2 & 0xF0 == 0xC0 => sw2 & 0x0F,
+        Some(CcidStatusError {
+            sw1: 0x69,
+            sw2: 0x83,
+        }) => 0,
+        _ => return Err(err.context("Failed to query PIV PIN retry counter")),
+    };
+    Ok(vec![PinRetries {
+        pin: "PIN",
+        remaining,
+    }])
+}
+
+fn admin_get_init_status(tx: &dyn CardChannel) -> anyhow::Result<u8> {
+    ccid_transmit(tx, 0x80, 0x00, 0x00, &[], Some(0))
+        .context("Failed to query device status")
+        .and_then(|response| {
+            response
+                .first()
+                .copied()
+                .context("Expected at least 1 status byte")
+        })
+}
+
+/// Reads a config value with the admin get config command.
+fn admin_get_config(tx: &dyn CardChannel, key: &str) -> anyhow::Result<String> {
+    let response = ccid_transmit(tx, 0x82, 0x00, 0x00, key.as_bytes(), Some(0))
+        .with_context(|| format!("Failed to query config value {}", key))?;
+    String::from_utf8(response).context("Config value is not valid UTF-8")
+}
+
+/// Reads the known config keys and the extra keys.  Keys that are unknown to the firmware
+/// are skipped.  If no key can be read, older firmware without config support is assumed.
+fn admin_get_config_values(
+    tx: &dyn CardChannel,
+    extra_keys: &[String],
+) -> Option<Vec<ConfigValue>> {
+    let known = KNOWN_CONFIG_KEYS
+        .iter()
+        .map(|(key, description)| (*key, Some(*description)));
+    let extra = extra_keys
+        .iter()
+        .filter(|key| !KNOWN_CONFIG_KEYS.iter().any(|(known, _)| known == key))
+        .map(|key| (key.as_str(), None));
+    let config: Vec<_> = known
+        .chain(extra)
+        .filter_map(|(key, description)| {
+            admin_get_config(tx, key).ok().map(|value| ConfigValue {
+                key: key.to_owned(),
+                description,
+                value,
+            })
+        })
+        .collect();
+    (!config.is_empty()).then_some(config)
+}
+
+/// Returns the touch configuration from the config values.
+fn touch_config(config: &[ConfigValue]) -> Option<BTreeMap<String, String>> {
+    let touch_config: BTreeMap<_, _> = config
+        .iter()
+        .filter(|value| TOUCH_CONFIG_KEYS.contains(&value.key.as_str()))
+        .map(|value| (value.key.clone(), value.value.clone()))
+        .collect();
+    (!touch_config.is_empty()).then_some(touch_config)
+}
+
+/// Checks whether the device is in an inconsistent state that can only be fixed by a
+/// factory reset.  This is the case if:
+/// - the init status reports that the internal or external filesystem could not be
+///   mounted, or
+/// - the provisioner applet is present and the init status reports any error, meaning
+///   that the device has only been partially provisioned.
+fn needs_factory_reset(init_status: Option<u8>, provisioner: bool) -> bool {
+    let init_status = init_status.unwrap_or_default();
+    let flash_error = INIT_STATUS_INTERNAL_FLASH_ERROR | INIT_STATUS_EXTERNAL_FLASH_ERROR;
+    init_status & flash_error != 0 || (provisioner && init_status != 0)
+}
+
+/// Queries the UUID with retries and an exponential backoff.  The first query after the
+/// select sometimes fails even though a retry works.
+fn admin_get_uuid_with_retries(
+    tx: &dyn CardChannel,
+    retries: u32,
+    delay: time::Duration,
+) -> anyhow::Result<Uuid> {
+    let mut delay = delay;
+    let mut attempt = 0;
+    loop {
+        match admin_get_uuid(tx) {
+            Ok(uuid) => return Ok(uuid),
+            Err(err) if attempt < retries => {
+                log!("UUID query failed, retrying in {:?}: {:#}", delay, err);
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(feature = "ccid")]
+fn get_firmware_reader(
+    mut reader: pcsc::Card,
+    name: &ffi::CStr,
+    options: &ProbeOptions,
+) -> anyhow::Result<FirmwareReader> {
+    let max_apdu_data_size = reader_get_max_apdu_data_size(&reader).ok();
+    let driver = reader_get_driver(&reader);
+    let contactless = reader
+        .status2_owned()
+        .map(|status| is_contactless_atr(status.atr()))
+        .ok();
+    recording_set_reader_info(max_apdu_data_size, &driver, contactless.unwrap_or_default());
+    let tx = reader
+        .transaction()
+        .map_err(|err| pcsc_error_context(err, "Failed to start smartcard transaction"))?;
+    let firmware_reader =
+        probe_firmware(&tx, name, options, max_apdu_data_size, driver, contactless)?;
+    // Release the transaction and the connection explicitly before the next reader is
+    // probed so that the card is not left locked for other clients
+    if let Err((_, err)) = tx.end(pcsc::Disposition::LeaveCard) {
+        log!(
+            "Reader {}: failed to end transaction: {}",
+            name.to_string_lossy(),
+            err
+        );
+    }
+    if let Err((_, err)) = reader.disconnect(options.disconnect_mode.into()) {
+        log!(
+            "Reader {}: failed to disconnect: {}",
+            name.to_string_lossy(),
+            err
+        );
+    }
+    Ok(firmware_reader)
+}
+
+/// Queries the firmware reader information over a channel to the card.
+fn probe_firmware(
+    tx: &dyn CardChannel,
+    name: &ffi::CStr,
+    options: &ProbeOptions,
+    max_apdu_data_size: Option<u32>,
+    driver: ReaderDriver,
+    contactless: Option<bool>,
+) -> anyhow::Result<FirmwareReader> {
+    let channel = SizedChannel {
+        channel: tx,
+        max_apdu_data_size,
+    };
+    let tx: &dyn CardChannel = &channel;
+    if let Err(err) = ccid_select(tx, AID_ADMIN) {
+        // The device answered, but it does not know the admin applet
+        if err.downcast_ref::<CcidStatusError>().is_some() {
+            return Err(err.context(IncompatibleDevice));
+        }
+        return Err(err.context("Failed to select admin application"));
+    }
+    let uuid = admin_get_uuid_with_retries(tx, options.uuid_retries, options.uuid_retry_delay)
+        .context("Failed to query UUID")?;
+    let (version, legacy) = match admin_get_version(tx) {
+        Ok(version) => (Some(version), false),
+        // 6D00 (instruction not supported) means that the firmware predates the command
+        Err(err) => (
+            None,
+            matches!(
+                err.downcast_ref(),
+                Some(CcidStatusError {
+                    sw1: 0x6D,
+                    sw2: 0x00
+                })
+            ),
+        ),
+    };
+    // Legacy firmware does not support any of the newer commands either
+    let (build_info, secure_boot, init_status, config, rng_self_test) =
+        if options.compat_mode || legacy {
+            (None, None, None, None, RngSelfTest::NotSupported)
+        } else {
+            (
+                admin_get_build_info(tx).ok().flatten(),
+                // Older firmware versions do not support this command
+                admin_get_secure_boot(tx).ok(),
+                admin_get_init_status(tx).ok(),
+                admin_get_config_values(tx, &options.config_keys),
+                admin_check_rng(tx),
+            )
+        };
+    let mut provisioner = false;
+    let mut applet_status = Vec::new();
+    for applet in &options.applets {
+        // The admin applet has already been selected successfully
+        let (state, select_response) = if applet.aid == AID_ADMIN {
+            (AppletState::Present, Vec::new())
+        } else {
+            ccid_probe(tx, applet)
+        };
+        let present = state != AppletState::Absent;
+        if applet.aid == AID_PROVISIONER {
+            provisioner = present;
+        }
+        // Only counters are read, the PINs are never verified
+        let pin_retries = match state {
+            AppletState::Present if applet.aid == AID_OPENPGP => openpgp_get_pin_retries(tx).ok(),
+            AppletState::Present if applet.aid == AID_PIV => piv_get_pin_retries(tx).ok(),
+            _ => None,
+        };
+        let fido_credentials = match state {
+            AppletState::Present if applet.aid == AID_FIDO => fido_get_credentials(tx).ok(),
+            _ => None,
+        };
+        // The FIDO attestation certificate is not checked.  It is only returned by
+        // makeCredential, which requires user presence, and the device does not expose its
+        // batch certificate in any other way.
+        let openpgp = match state {
+            AppletState::Present if applet.aid == AID_OPENPGP => openpgp_get_data(tx).ok(),
+            _ => None,
+        };
+        let secrets_credentials = match state {
+            AppletState::Present if applet.aid == AID_SECRETS => secrets_get_credentials(tx).ok(),
+            _ => None,
+        };
+        let applet_version = match state {
+            AppletState::Present if applet.aid == AID_OPENPGP => openpgp_get_version(tx).ok(),
+            AppletState::Present if applet.aid == AID_SECRETS => {
+                secrets_parse_version(&select_response)
+            }
+            _ => None,
+        };
+        applet_status.push(AppletStatus {
+            name: applet.name.clone(),
+            aid: format_hex(&applet.aid),
+            present,
+            blocked: state == AppletState::Blocked,
+            version: applet_version,
+            pin_retries: pin_retries.unwrap_or_default(),
+            fido_credentials,
+            openpgp,
+            secrets_credentials,
+        });
+    }
+    Ok(FirmwareReader {
+        name: name.to_owned(),
+        uuid,
+        version,
+        version_status: version.map(|version| version.status()),
+        legacy,
+        build_info,
+        provisioner,
+        secure_boot,
+        init_status,
+        needs_factory_reset: needs_factory_reset(init_status, provisioner),
+        rng_self_test,
+        touch_config: config.as_deref().and_then(touch_config),
+        config,
+        max_apdu_data_size,
+        driver,
+        contactless,
+        nfc_quality: (contactless == Some(true) && options.nfc_quality_probes > 0)
+            .then(|| nfc_get_quality(tx, options.nfc_quality_probes)),
+        applets: applet_status,
+    })
+}
+
+/// Checks the random number generator by requesting random data twice.  The output of a
+/// healthy generator is never constant or repeated.
+fn admin_check_rng(tx: &dyn CardChannel) -> RngSelfTest {
+    let mut samples = Vec::new();
+    for _ in 0..2 {
+        match ccid_transmit(tx, 0x60, 0x00, 0x00, &[], Some(0)) {
+            Ok(sample) => samples.push(sample),
+            // A transmission error does not mean that the generator is broken
+            Err(err) => {
+                log!("Failed to query random data: {:#}", err);
+                return RngSelfTest::NotSupported;
+            }
+        }
+    }
+    let healthy = |sample: &[u8]| sample.len() >= 16 && sample.iter().any(|b| *b != sample[0]);
+    if samples.iter().all(|sample| healthy(sample)) && samples[0] != samples[1] {
+        RngSelfTest::Passed
+    } else {
+        RngSelfTest::Failed
+    }
+}
+
+#[cfg(feature = "ccid")]
+/// Returns true if the ATR is a pseudo-ATR for a contactless card as defined in PC/SC
+/// part 3, i. e. if the device is connected via NFC.
+fn is_contactless_atr(atr: &[u8]) -> bool {
+    matches!(atr, [0x3B, t0, 0x80, 0x01, ..] if t0 & 0xF0 == 0x80)
+}
+
+/// Rates the NFC connection by repeatedly selecting the admin application and counting the
+/// failures.
+fn nfc_get_quality(tx: &dyn CardChannel, probes: u32) -> NfcQualityReport {
+    let errors = (0..probes)
+        .map(|_| u32::from(ccid_select(tx, AID_ADMIN).is_err()))
+        .sum();
+    NfcQualityReport {
+        probes,
+        errors,
+        quality: NfcQuality::new(probes, errors),
+    }
+}
+
+#[cfg(feature = "ccid")]
+fn probe_firmware_reader(ctx: &pcsc::Context, name: &ffi::CStr, options: &ProbeOptions) -> Reader {
+    apdu_log_set_reader(name);
+    recording_add_reader(name);
+    let card = match ctx.connect(name, pcsc::ShareMode::Shared, pcsc::Protocols::T1) {
+        Ok(card) => card,
+        Err(err) => {
+            // NFC readers are listed permanently, but the device is only there while it is
+            // presented to the reader
+            let no_card = err == pcsc::Error::NoSmartcard;
+            // The reader can disappear between listing and connecting
+            let failure = if is_removal_error(err) {
+                ReaderFailure::Removed
+            } else {
+                ReaderFailure::ConnectFailed
+            };
+            let error = pcsc_error_context(err, "Failed to connect to smartcard reader");
+            log!("Reader {}: {:#}", name.to_string_lossy(), error);
+            recording_set_connect_error(&error, no_card);
+            if no_card {
+                return Reader::Empty(get_empty_reader(ctx, name));
+            }
+            return Reader::Unsupported(UnsupportedReader {
+                name: name.to_owned(),
+                error,
+                failure,
+            });
+        }
+    };
+    probe_reader(name, || get_firmware_reader(card, name, options))
+}
+
+/// Queries the properties of a reader without a card with a direct connection.
+#[cfg(feature = "ccid")]
+fn get_empty_reader(ctx: &pcsc::Context, name: &ffi::CStr) -> EmptyReader {
+    let mut empty_reader = EmptyReader {
+        name: name.to_owned(),
+        driver: None,
+        max_apdu_data_size: None,
+    };
+    match ctx.connect(name, pcsc::ShareMode::Direct, pcsc::Protocols::UNDEFINED) {
+        Ok(card) => {
+            let driver = reader_get_driver(&card);
+            let max_apdu_data_size = reader_get_max_apdu_data_size(&card).ok();
+            recording_set_reader_info(max_apdu_data_size, &driver, false);
+            empty_reader.driver = Some(driver);
+            empty_reader.max_apdu_data_size = max_apdu_data_size;
+        }
+        Err(err) => log!(
+            "Reader {}: direct connection failed: {}",
+            name.to_string_lossy(),
+            err
+        ),
+    }
+    empty_reader
+}
+
+/// Runs the probe of a firmware reader and classifies its result.
+fn probe_reader(
+    name: &ffi::CStr,
+    probe: impl FnOnce() -> anyhow::Result<FirmwareReader>,
+) -> Reader {
+    let unsupported = |error, failure| {
+        Reader::Unsupported(UnsupportedReader {
+            name: name.to_owned(),
+            error,
+            failure,
+        })
+    };
+    // A buggy driver should not abort the scan of the other readers.  The probe only uses
+    // its own connection, so no broken state can be observed after a panic.
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(probe));
+    match result {
+        Ok(Ok(reader)) => {
+            log!(
+                "Reader {}: firmware device with uuid {}",
+                name.to_string_lossy(),
+                reader.uuid
+            );
+            Reader::Firmware(Box::new(reader))
+        }
+        Ok(Err(err)) => {
+            log!("Reader {}: probe failed: {:#}", name.to_string_lossy(), err);
+            let failure = if err.downcast_ref::<IncompatibleDevice>().is_some() {
+                ReaderFailure::Incompatible
+            } else if err.downcast_ref::<DeviceRemoved>().is_some() {
+                ReaderFailure::Removed
+            } else {
+                ReaderFailure::ProtocolFailed
+            };
+            unsupported(err, failure)
+        }
+        Err(_) => unsupported(
+            anyhow::anyhow!(
+                "Internal error during probe of reader {}",
+                name.to_string_lossy()
+            ),
+            ReaderFailure::ProtocolFailed,
+        ),
+    }
+}
+
+/// Probes a reader of a recording with the recorded exchanges.
+fn replay_firmware_reader(
+    recording: &Recording,
+    name: &ffi::CStr,
+    options: &ProbeOptions,
+) -> Reader {
+    apdu_log_set_reader(name);
+    let raw_name = format_hex(name.to_bytes());
+    let connect_error = match recording
+        .readers
+        .iter()
+        .find(|reader| reader.name == raw_name)
+    {
+        Some(reader @ RecordedReader { no_card: true, .. }) => {
+            return Reader::Empty(EmptyReader {
+                name: name.to_owned(),
+                driver: reader.driver.clone(),
+                max_apdu_data_size: reader.max_apdu_data_size,
+            })
+        }
+        Some(RecordedReader {
+            connect_error: Some(error),
+            ..
+        }) => anyhow::anyhow!("{}", error),
+        Some(reader) => {
+            let channel = ReplayChannel::new(&reader.exchanges);
+            return probe_reader(name, || {
+                let driver = reader.driver.clone().unwrap_or_default();
+                probe_firmware(
+                    &channel,
+                    name,
+                    options,
+                    reader.max_apdu_data_size,
+                    driver,
+                    Some(reader.contactless),
+                )
+            });
+        }
+        None => anyhow::anyhow!("The reader was not probed in the recording"),
+    };
+    Reader::Unsupported(UnsupportedReader {
+        name: name.to_owned(),
+        error: connect_error,
+        failure: ReaderFailure::ConnectFailed,
+    })
+}
+
+#[cfg(feature = "ccid")]
+fn get_readers(ctx: &pcsc::Context, options: &ProbeOptions) -> anyhow::Result<Vec<Reader>> {
+    let readers = match ctx.list_readers_owned() {
+        // pcsc-lite reports an error instead of an empty list if there are no readers
+        Err(pcsc::Error::NoReadersAvailable) => Vec::new(),
+        readers => readers.context("Failed to list pcsc readers")?,
+    };
+    recording_set_reader_names(&readers);
+    probe_readers(readers, options, |name| {
+        probe_firmware_reader(ctx, name, options)
+    })
+}
+
+/// Probes the readers with the firmware reader name and the forced readers.
+fn probe_readers(
+    mut readers: Vec<ffi::CString>,
+    options: &ProbeOptions,
+    mut probe: impl FnMut(&ffi::CStr) -> Reader,
+) -> anyhow::Result<Vec<Reader>> {
+    // Forced readers that are not listed are still probed so that the connection error is
+    // reported.
+    for name in &options.forced_readers {
+        if !readers.contains(name) {
+            readers.push(name.clone());
+        }
+    }
+    let count = readers.len();
+    log!("Found {} smartcard reader(s)", count);
+    let mut probed = Vec::new();
+    for (i, reader) in readers.into_iter().enumerate() {
+        if options.progress {
+            // The progress line is overwritten by the next one and cleared at the end
+            eprint!("\r\x1b[2KProbing reader {}/{}…", i + 1, count);
+        }
+        let reader = if is_firmware_reader_name(&reader) || options.forced_readers.contains(&reader)
+        {
+            probe(&reader)
+        } else {
+            Reader::Other(reader)
+        };
+        if let (true, Reader::Unsupported(reader)) = (options.strict, &reader) {
+            if options.progress {
+                eprint!("\r\x1b[2K");
+            }
+            anyhow::bail!(
+                "Aborting because of --strict: smartcard reader {} could not be probed: {:#}",
+                reader.name.to_string_lossy(),
+                reader.error
+            );
+        }
+        let found = matches!(&reader, Reader::Firmware(reader) if !reader.uuid.is_unset());
+        probed.push(reader);
+        if options.first_match && found {
+            log!("Found a firmware device, skipping the remaining readers");
+            break;
+        }
+    }
+    if options.progress && count > 0 {
+        eprint!("\r\x1b[2K");
+    }
+    Ok(probed)
+}
+
+fn get_warnings(
+    devices: &[Device],
+    reader_status: &ReaderStatus,
+    allow_provisioner: bool,
+) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    for device in devices {
+        if let Device::Recovery { .. } = device {
+            warnings.push(Warning::RecoveryDevice {
+                device: device.to_string(),
+            });
+        }
+    }
+    for device in devices {
+        if let Device::Firmware {
+            ccid_interface: Some(false),
+            ..
+        } = device
+        {
+            warnings.push(Warning::NoCcidInterface {
+                device: device.to_string(),
+            });
+        }
+    }
+    let firmware_device_count = devices
+        .iter()
+        .filter(|device| matches!(device, Device::Firmware { .. }))
+        .count();
+    // Devices without a CCID interface are reported separately
+    let reader_device_count = devices
+        .iter()
+        .filter(|device| device.expects_reader())
+        .count();
+    if reader_device_count > reader_status.firmware_readers.len() {
+        warnings.push(Warning::UnreachableDevice);
+    }
+    // If the CCID interface is known to exist, a reader that cannot be connected points to a
+    // stuck interface rather than a missing one
+    let ccid_device_count = devices
+        .iter()
+        .filter(|device| {
+            matches!(
+                device,
+                Device::Firmware {
+                    ccid_interface: Some(true),
+                    ..
+                }
+            )
+        })
+        .count();
+    if ccid_device_count > reader_status.firmware_readers.len() {
+        for reader in &reader_status.connect_failed_readers {
+            warnings.push(Warning::StuckCcidInterface {
+                reader: reader.name.to_string_lossy().into_owned(),
+            });
+        }
+    }
+    if firmware_device_count > 1 {
+        warnings.push(Warning::MultipleDevices);
+    }
+    for name in &reader_status.other_readers {
+        warnings.push(Warning::UnsupportedReader {
+            reader: name.to_string_lossy().into_owned(),
+        });
+    }
+    for reader in &reader_status.stale_readers {
+        warnings.push(Warning::StaleReader {
+            reader: reader.name.to_string_lossy().into_owned(),
+        });
+    }
+    for reader in &reader_status.firmware_readers {
+        let uuid = reader.uuid;
+        let name = reader.name.to_string_lossy().into_owned();
+        if reader.provisioner && !allow_provisioner {
+            warnings.push(Warning::ProvisionerFirmware {
+                uuid,
+                reader: name.clone(),
+            });
+        }
+        if let Some(version) = reader.version {
+            if let Some(known) = version.known_version() {
+                warnings.extend(known.warning(uuid, name.clone(), version));
+            }
+        }
+        // The provisioner firmware is used before the device is locked, so secure boot is
+        // expected to be disabled there.
+        if reader.secure_boot == Some(false) && !reader.provisioner {
+            warnings.push(Warning::SecureBootDisabled {
+                uuid,
+                reader: name.clone(),
+            });
+        }
+    }
+    let mut versions = BTreeMap::<_, Vec<_>>::new();
+    for reader in &reader_status.firmware_readers {
+        if let Some(version) = reader.version {
+            versions.entry(version).or_default().push(reader.uuid);
+        }
+    }
+    if versions.len() > 1 {
+        warnings.push(Warning::MixedFirmwareVersions { versions });
+    }
+    for reader in &reader_status.firmware_readers {
+        let uuid = reader.uuid;
+        let name = reader.name.to_string_lossy().into_owned();
+        if uuid.is_unset() {
+            warnings.push(Warning::UnsetUuid {
+                uuid,
+                reader: name.clone(),
+            });
+        }
+        if reader.rng_self_test == RngSelfTest::Failed {
+            warnings.push(Warning::RngSelfTestFailed {
+                uuid,
+                reader: name.clone(),
+            });
+        }
+        for applet in reader.applets.iter().filter(|applet| applet.blocked) {
+            warnings.push(Warning::BlockedApplet {
+                uuid,
+                reader: name.clone(),
+                applet: applet.name.clone(),
+            });
+        }
+        for applet in &reader.applets {
+            for pin in applet.pin_retries.iter().filter(|pin| pin.remaining == 0) {
+                warnings.push(Warning::BlockedPin {
+                    uuid,
+                    reader: name.clone(),
+                    applet: applet.name.clone(),
+                    pin: pin.pin,
+                });
+            }
+        }
+        if reader.needs_factory_reset {
+            warnings.push(Warning::NeedsFactoryReset {
+                uuid,
+                reader: name,
+                init_status: reader.init_status.unwrap_or_default(),
+            });
+        }
+    }
+    for (uuid, names) in find_duplicate_uuids(&reader_status.firmware_readers) {
+        for name in names {
+            warnings.push(Warning::DuplicateUuid {
+                uuid,
+                reader: name.to_string_lossy().into_owned(),
+            });
+        }
+    }
+    warnings
+}
+
+fn print_bootloader_memory(out: &mut dyn io::Write, memory: &BootloaderMemory) -> io::Result<()> {
+    let format = |value: Option<usize>, hex: bool| match value {
+        Some(value) if hex => format!("{:#010x}", value),
+        Some(value) => format!("{} bytes", value),
+        None => "unknown".to_owned(),
+    };
+    writeln!(
+        out,
+        "  flash: start {}, size {}, page size {}, sector size {}",
+        format(memory.flash_start_address, true),
+        format(memory.flash_size, false),
+        format(memory.flash_page_size, false),
+        format(memory.flash_sector_size, false)
+    )?;
+    writeln!(
+        out,
+        "  RAM: start {}, size {}",
+        format(memory.ram_start_address, true),
+        format(memory.ram_size, false)
+    )?;
+    for (start, end) in &memory.reserved_regions {
+        writeln!(out, "  reserved region: {:#010x}-{:#010x}", start, end)?;
+    }
+    Ok(())
+}
+
+fn print_descriptors(out: &mut dyn io::Write, descriptors: &UsbDescriptors) -> io::Result<()> {
+    let d = descriptors;
+    writeln!(out, "  Device descriptor:")?;
+    writeln!(out, "    bcdUSB {}", d.usb_version)?;
+    writeln!(
+        out,
+        "    class {:#04x}, subclass {:#04x}, protocol {:#04x}",
+        d.class_code, d.sub_class_code, d.protocol_code
+    )?;
+    writeln!(out, "    bMaxPacketSize0 {}", d.max_packet_size)?;
+    writeln!(
+        out,
+        "    idVendor {:04x}, idProduct {:04x}",
+        d.vendor_id, d.product_id
+    )?;
+    writeln!(out, "    bcdDevice {}", d.device_version)?;
+    for config in &d.configurations {
+        writeln!(out, "    Configuration {}:", config.number)?;
+        writeln!(
+            out,
+            "      max power {} mA, self powered {}, remote wakeup {}",
+            config.max_power, config.self_powered, config.remote_wakeup
+        )?;
+        for interface in &config.interfaces {
+            writeln!(
+                out,
+                "      Interface {} (alternate setting {}):",
+                interface.number, interface.setting_number
+            )?;
+            writeln!(
+                out,
+                "        class {:#04x}, subclass {:#04x}, protocol {:#04x}",
+                interface.class_code, interface.sub_class_code, interface.protocol_code
+            )?;
+            for endpoint in &interface.endpoints {
+                writeln!(
+                    out,
+                    "        Endpoint {:#04x}: {} {}, sync {}, usage {}, max packet size {}, interval {}",
+                    endpoint.address,
+                    endpoint.direction,
+                    endpoint.transfer_type,
+                    endpoint.sync_type,
+                    endpoint.usage_type,
+                    endpoint.max_packet_size,
+                    endpoint.interval
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_text(out: &mut dyn io::Write, diagnosis: &Diagnosis, verbose: bool) -> io::Result<()> {
+    let reader_status = &diagnosis.reader_status;
+
+    let environment = &diagnosis.environment;
+    let libusb = match diagnosis.libusb {
+        LibusbStatus::NotUsed => "not used",
+        LibusbStatus::Ok { .. } => "available",
+        LibusbStatus::Error { .. } => "unavailable",
+    };
+    writeln!(
+        out,
+        "Environment: {} {}, pcsc {}, libusb {}",
+        environment.os,
+        environment.arch,
+        if environment.pcsc_available {
+            "available"
+        } else {
+            "unavailable"
+        },
+        libusb
+    )?;
+
+    if verbose {
+        writeln!(out, "Generated at: {}", diagnosis.generated_at)?;
+        writeln!(
+            out,
+            "Hostname: {}",
+            diagnosis.hostname.as_deref().unwrap_or("unknown")
+        )?;
+        writeln!(out, "USB backend: {}", diagnosis.usb_backend)?;
+        match &diagnosis.libusb {
+            LibusbStatus::NotUsed => writeln!(out, "libusb: not used")?,
+            LibusbStatus::Ok { device_count } => {
+                writeln!(out, "libusb: ok, {} USB device(s)", device_count)?
+            }
+            LibusbStatus::Error { error } => writeln!(out, "libusb: error: {}", error)?,
+        }
+    }
+
+    if diagnosis.usb_backend == UsbBackend::Sysfs {
+        writeln!(
+            out,
+            "Note: The firmware devices were found in sysfs as libusb and hidapi did not list them."
+        )?;
+    }
+    writeln!(out, "{} device(s) found:", diagnosis.devices.len())?;
+    for device in &diagnosis.devices {
+        writeln!(out, "- {}", device)?;
+        match device {
+            Device::Firmware {
+                descriptors: Some(descriptors),
+                ..
+            } => print_descriptors(out, descriptors)?,
+            Device::Firmware {
+                descriptors_error: Some(error),
+                ..
+            } => writeln!(out, "  descriptors unavailable: {}", error)?,
+            Device::Bootloader {
+                memory: Some(memory),
+                ..
+            } => print_bootloader_memory(out, memory)?,
+            _ => {}
+        }
+    }
+
+    if !diagnosis.logical_devices.is_empty() {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{} physical device(s) found:",
+            diagnosis.logical_devices.len()
+        )?;
+        for logical_device in &diagnosis.logical_devices {
+            match logical_device.uuid {
+                Some(uuid) => write!(out, "- uuid {}", uuid)?,
+                None => write!(out, "- unknown uuid")?,
+            }
+            writeln!(out, " in {} mode", logical_device.mode)?;
+            for i in &logical_device.devices {
+                match &diagnosis.devices[*i] {
+                    Device::Bootloader { path, .. } => {
+                        writeln!(out, "  HID device at path {}", path)?
+                    }
+                    device @ Device::Recovery { .. } => {
+                        writeln!(out, "  {}", device)?;
+                        writeln!(out, "  {}", RECOVERY_HINT)?;
+                    }
+                    Device::Firmware {
+                        bus: Some(bus),
+                        address: Some(address),
+                        ..
+                    } => writeln!(out, "  USB device on bus {:03} device {:03}", bus, address)?,
+                    Device::Firmware {
+                        path: Some(path), ..
+                    } => writeln!(out, "  USB device at path {}", path)?,
+                    Device::Firmware { .. } => writeln!(out, "  USB device")?,
+                }
+            }
+            for i in &logical_device.readers {
+                let reader = &reader_status.firmware_readers[*i];
+                writeln!(out, "  reader {}", reader.name.to_string_lossy())?;
+            }
+            if let Some(versions) = &logical_device.versions {
+                writeln!(
+                    out,
+                    "  bootloader {}, firmware {}: {}",
+                    versions.bootloader, versions.firmware, versions.compatibility
+                )?;
+            }
+        }
+    }
+
+    match diagnosis.reader_state {
+        ReaderState::NoReaders => {
+            writeln!(out)?;
+            writeln!(out, "No smartcard readers detected.")?;
+        }
+        ReaderState::OnlyOtherReaders => {
+            writeln!(out)?;
+            writeln!(
+                out,
+                "Smartcard readers detected, but none of them belongs to a Nitrokey 3."
+            )?;
+        }
+        ReaderState::FirmwareFound => {}
+    }
+
+    if !reader_status.firmware_readers.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "Firmware status:")?;
+        for (i, reader) in reader_status.firmware_readers.iter().enumerate() {
+            writeln!(out, "- [{}] {}", i + 1, reader)?;
+            for applet in &reader.applets {
+                for pin in &applet.pin_retries {
+                    if pin.remaining == 0 {
+                        writeln!(out, "  {} {}: 0 remaining — blocked", applet.name, pin.pin)?;
+                    } else {
+                        writeln!(
+                            out,
+                            "  {} {}: {} remaining",
+                            applet.name, pin.pin, pin.remaining
+                        )?;
+                    }
+                }
+                if let Some(credentials) = &applet.fido_credentials {
+                    match credentials.existing {
+                        Some(existing) => {
+                            writeln!(out, "  {} credentials: {} existing", applet.name, existing)?
+                        }
+                        None => writeln!(
+                            out,
+                            "  {} credentials: existing count requires PIN, skipped",
+                            applet.name
+                        )?,
+                    }
+                    match credentials.remaining {
+                        Some(remaining) => writeln!(
+                            out,
+                            "  {} credentials: {} remaining",
+                            applet.name, remaining
+                        )?,
+                        None => writeln!(out, "  {} credentials: remaining unknown", applet.name)?,
+                    }
+                }
+                if let Some(credentials) = &applet.secrets_credentials {
+                    match (credentials.totp, credentials.hotp, credentials.other) {
+                        (Some(totp), Some(hotp), Some(other)) => writeln!(
+                            out,
+                            "  {} credentials: {} TOTP, {} HOTP, {} other",
+                            applet.name, totp, hotp, other
+                        )?,
+                        _ => writeln!(out, "  {} credentials: requires PIN, skipped", applet.name)?,
+                    }
+                }
+                if let Some(openpgp) = &applet.openpgp {
+                    writeln!(
+                        out,
+                        "  {} cardholder: {}",
+                        applet.name,
+                        openpgp.cardholder_name.as_deref().unwrap_or("not set")
+                    )?;
+                    writeln!(
+                        out,
+                        "  {} URL: {}",
+                        applet.name,
+                        openpgp.url.as_deref().unwrap_or("not set")
+                    )?;
+                    if openpgp.fingerprints.is_empty() {
+                        writeln!(out, "  {} keys: none", applet.name)?;
+                    }
+                    for fingerprint in &openpgp.fingerprints {
+                        writeln!(
+                            out,
+                            "  {} {} key: {}",
+                            applet.name, fingerprint.key, fingerprint.fingerprint
+                        )?;
+                    }
+                }
+            }
+            match &reader.config {
+                Some(config) => {
+                    for value in config {
+                        match value.description {
+                            Some(description) => writeln!(
+                                out,
+                                "  config {} ({}): {}",
+                                value.key, description, value.value
+                            )?,
+                            None => writeln!(out, "  config {}: {}", value.key, value.value)?,
+                        }
+                    }
+                }
+                None => writeln!(out, "  config: unsupported")?,
+            }
+            writeln!(out, "  RNG self-test: {}", reader.rng_self_test)?;
+            if let Some(nfc_quality) = &reader.nfc_quality {
+                writeln!(
+                    out,
+                    "  NFC connection: {} ({}/{} test commands failed)",
+                    nfc_quality.quality, nfc_quality.errors, nfc_quality.probes
+                )?;
+            }
+            if verbose {
+                writeln!(
+                    out,
+                    "  build: {}",
+                    reader.build_info.as_deref().unwrap_or("not available")
+                )?;
+                for applet in reader.applets.iter().filter(|applet| applet.present) {
+                    writeln!(
+                        out,
+                        "  {} version: {}",
+                        applet.name,
+                        applet.version.as_deref().unwrap_or("unknown")
+                    )?;
+                }
+                match reader.max_apdu_data_size {
+                    Some(size) => writeln!(out, "  max APDU data size: {}", size)?,
+                    None => writeln!(out, "  max APDU data size: unknown")?,
+                }
+                let unknown = "unknown";
+                writeln!(
+                    out,
+                    "  driver: vendor {}, IFD type {}, IFD version {}",
+                    reader.driver.vendor.as_deref().unwrap_or(unknown),
+                    reader.driver.ifd_type.as_deref().unwrap_or(unknown),
+                    reader.driver.ifd_version.as_deref().unwrap_or(unknown)
+                )?;
+            }
+        }
+    }
+
+    if !reader_status.firmware_readers.is_empty() {
+        let counts: Vec<_> = diagnosis
+            .applet_summary
+            .iter()
+            .map(|count| format!("{} have {}", count.present, count.name))
+            .collect();
+        writeln!(out)?;
+        writeln!(
+            out,
+            "Applet summary: {} key(s): {}",
+            reader_status.firmware_readers.len(),
+            counts.join(", ")
+        )?;
+    }
+
+    for (title, readers) in [
+        ("Connection failures", &reader_status.connect_failed_readers),
+        ("Protocol failures", &reader_status.protocol_failed_readers),
+        ("Incompatible devices", &reader_status.incompatible_readers),
+        ("Removed during scan", &reader_status.removed_readers),
+    ] {
+        if !readers.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "{}:", title)?;
+            for reader in readers {
+                writeln!(
+                    out,
+                    "- {}: {:#}",
+                    reader.name.to_string_lossy(),
+                    reader.error
+                )?;
+            }
+        }
+    }
+
+    if !reader_status.stale_readers.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "Stale readers:")?;
+        for reader in &reader_status.stale_readers {
+            writeln!(
+                out,
+                "- {}: {:#}",
+                reader.name.to_string_lossy(),
+                reader.error
+            )?;
+        }
+    }
+
+    if !reader_status.empty_readers.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "Readers without a device:")?;
+        for reader in &reader_status.empty_readers {
+            writeln!(
+                out,
+                "- {}: reader present, but no device presented (NFC: tap the key)",
+                reader.name.to_string_lossy()
+            )?;
+            if let (true, Some(driver)) = (verbose, &reader.driver) {
+                let unknown = "unknown";
+                writeln!(
+                    out,
+                    "  driver: vendor {}, IFD type {}, IFD version {}",
+                    driver.vendor.as_deref().unwrap_or(unknown),
+                    driver.ifd_type.as_deref().unwrap_or(unknown),
+                    driver.ifd_version.as_deref().unwrap_or(unknown)
+                )?;
+                match reader.max_apdu_data_size {
+                    Some(size) => writeln!(out, "  max APDU data size: {}", size)?,
+                    None => writeln!(out, "  max APDU data size: unknown")?,
+                }
+            }
+        }
+    }
+
+    if let Some(uuid_check) = &diagnosis.uuid_check {
+        for (title, uuids) in &[
+            ("Present", &uuid_check.present),
+            ("Missing", &uuid_check.missing),
+            ("Unexpected", &uuid_check.unexpected),
+        ] {
+            writeln!(out)?;
+            writeln!(out, "{} UUIDs: {}", title, uuids.len())?;
+            for uuid in uuids.iter() {
+                writeln!(out, "- {}", uuid)?;
+            }
+        }
+    }
+
+    if let Some(state_delta) = &diagnosis.state_delta {
+        for (title, uuids) in &[
+            ("Newly seen", &state_delta.added),
+            ("No longer present", &state_delta.removed),
+        ] {
+            writeln!(out)?;
+            writeln!(out, "{} UUIDs since the last run: {}", title, uuids.len())?;
+            for uuid in uuids.iter() {
+                writeln!(out, "- {}", uuid)?;
+            }
+        }
+    }
+
+    writeln!(out)?;
+    for warning in &diagnosis.warnings {
+        writeln!(out, "Warning: {}", warning)?;
+    }
+
+    Ok(())
+}
+
+/// Quotes a value for the shell.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Returns a single line summary of the diagnosis, e. g. `NK3: 1 ok (1.7.0) ✓`.  If there
+/// are warnings, the devices are marked with `!` and the reason is `busy` if a reader
+/// could not be connected.
+fn oneline_summary(diagnosis: &Diagnosis) -> String {
+    let count = diagnosis.logical_devices.len();
+    if count == 0 {
+        return "NK3: none".to_owned();
+    }
+    let warnings = diagnosis
+        .warnings
+        .iter()
+        .filter(|warning| warning.severity() > Severity::Info)
+        .count();
+    if warnings > 0 {
+        let reason = if diagnosis.reader_status.connect_failed_readers.is_empty() {
+            format!("{} warning(s)", warnings)
+        } else {
+            "busy".to_owned()
+        };
+        return format!("NK3: {}! ({})", count, reason);
+    }
+    let versions: BTreeSet<_> = diagnosis
+        .reader_status
+        .firmware_readers
+        .iter()
+        .filter_map(|reader| reader.version)
+        .map(|version| version.to_string())
+        .collect();
+    if versions.is_empty() {
+        format!("NK3: {} ok ✓", count)
+    } else {
+        let versions: Vec<_> = versions.into_iter().collect();
+        format!("NK3: {} ok ({}) ✓", count, versions.join(", "))
+    }
+}
+
+/// Prints shell variable assignments for the firmware devices.  If there is more than one
+/// device, the variables are indexed.
+fn print_env(out: &mut dyn io::Write, diagnosis: &Diagnosis) -> io::Result<()> {
+    let readers = &diagnosis.reader_status.firmware_readers;
+    writeln!(out, "NK3_DEVICE_COUNT={}", readers.len())?;
+    for (i, reader) in readers.iter().enumerate() {
+        let suffix = if readers.len() > 1 {
+            format!("_{}", i)
+        } else {
+            String::new()
+        };
+        let version = reader
+            .version
+            .map(|version| version.to_string())
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "NK3_UUID{}={}",
+            suffix,
+            shell_quote(&reader.uuid.to_string())
+        )?;
+        writeln!(
+            out,
+            "NK3_FIRMWARE_VERSION{}={}",
+            suffix,
+            shell_quote(&version)
+        )?;
+    }
+    Ok(())
+}
+
+/// Escapes a Prometheus label value.
+fn prometheus_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn print_prometheus_metric(
+    out: &mut dyn io::Write,
+    name: &str,
+    help: &str,
+    samples: &[(String, usize)],
+) -> io::Result<()> {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} gauge", name)?;
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            writeln!(out, "{} {}", name, value)?;
+        } else {
+            writeln!(out, "{}{{{}}} {}", name, labels, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the diagnosis as metrics in the Prometheus text exposition format.
+fn print_prometheus(out: &mut dyn io::Write, diagnosis: &Diagnosis) -> io::Result<()> {
+    let reader_status = &diagnosis.reader_status;
+    let reader_labels = |uuid: Option<Uuid>, name: &ffi::CStr| {
+        format!(
+            "uuid=\"{}\",reader=\"{}\"",
+            uuid.map(|uuid| uuid.to_string()).unwrap_or_default(),
+            prometheus_escape(&name.to_string_lossy())
+        )
+    };
+
+    print_prometheus_metric(
+        out,
+        "nk3_devices_total",
+        "Number of detected USB devices",
+        &[(String::new(), diagnosis.devices.len())],
+    )?;
+    print_prometheus_metric(
+        out,
+        "nk3_firmware_readers_total",
+        "Number of smartcard readers of firmware devices that could be probed",
+        &[(String::new(), reader_status.firmware_readers.len())],
+    )?;
+    let reachable: Vec<_> = reader_status
+        .firmware_readers
+        .iter()
+        .map(|reader| (reader_labels(Some(reader.uuid), &reader.name), 1))
+        .chain(
+            reader_status
+                .unsupported_readers()
+                .chain(&reader_status.stale_readers)
+                .map(|reader| (reader_labels(None, &reader.name), 0)),
+        )
+        .collect();
+    print_prometheus_metric(
+        out,
+        "nk3_reader_reachable",
+        "Whether the admin applet of a firmware reader could be reached",
+        &reachable,
+    )?;
+    let versions: Vec<_> = reader_status
+        .firmware_readers
+        .iter()
+        .filter_map(|reader| {
+            reader.version.map(|version| {
+                (
+                    format!("uuid=\"{}\",version=\"{}\"", reader.uuid, version),
+                    1,
+                )
+            })
+        })
+        .collect();
+    print_prometheus_metric(
+        out,
+        "nk3_firmware_version_info",
+        "Firmware version of a device",
+        &versions,
+    )?;
+    print_prometheus_metric(
+        out,
+        "nk3_warnings_total",
+        "Number of emitted warnings",
+        &[(String::new(), diagnosis.warnings.len())],
+    )
+}
+
+/// A line of the --format ndjson output
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum NdjsonLine<'a> {
+    /// A physical device with its USB devices, readers and warnings
+    Device {
+        uuid: Option<Uuid>,
+        mode: DeviceMode,
+        versions: Option<&'a DeviceVersions>,
+        usb_devices: Vec<&'a Device>,
+        readers: Vec<&'a FirmwareReader>,
+        warnings: Vec<&'a Warning>,
+    },
+    /// The context of the scan and the warnings that do not belong to a device
+    Summary {
+        schema_version: u32,
+        generated_at: &'a str,
+        hostname: Option<&'a str>,
+        device_count: usize,
+        reader_state: ReaderState,
+        warnings: Vec<&'a Warning>,
+    },
+}
+
+/// Prints one line per physical device and a summary line.  Each line is a complete JSON
+/// object.
+fn print_ndjson(out: &mut dyn io::Write, diagnosis: &Diagnosis) -> anyhow::Result<()> {
+    let uuids: BTreeSet<_> = diagnosis
+        .logical_devices
+        .iter()
+        .filter_map(|logical_device| logical_device.uuid)
+        .collect();
+    let mut lines: Vec<_> = diagnosis
+        .logical_devices
+        .iter()
+        .map(|logical_device| NdjsonLine::Device {
+            uuid: logical_device.uuid,
+            mode: logical_device.mode,
+            versions: logical_device.versions.as_ref(),
+            usb_devices: logical_device
+                .devices
+                .iter()
+                .map(|i| &diagnosis.devices[*i])
+                .collect(),
+            readers: logical_device
+                .readers
+                .iter()
+                .map(|i| &diagnosis.reader_status.firmware_readers[*i])
+                .collect(),
+            warnings: diagnosis
+                .warnings
+                .iter()
+                .filter(|warning| warning.uuid().is_some() && warning.uuid() == logical_device.uuid)
+                .collect(),
+        })
+        .collect();
+    lines.push(NdjsonLine::Summary {
+        schema_version: SCHEMA_VERSION,
+        generated_at: &diagnosis.generated_at,
+        hostname: diagnosis.hostname.as_deref(),
+        device_count: diagnosis.logical_devices.len(),
+        reader_state: diagnosis.reader_state,
+        warnings: diagnosis
+            .warnings
+            .iter()
+            .filter(|warning| !warning.uuid().is_some_and(|uuid| uuids.contains(&uuid)))
+            .collect(),
+    });
+    for line in &lines {
+        serde_json::to_writer(&mut *out, line).context("Failed to serialize diagnosis")?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Prints one row per physical device as CSV.  All fields are quoted so that spreadsheet
+/// applications do not interpret UUIDs as numbers.
+fn print_csv(out: &mut dyn io::Write, diagnosis: &Diagnosis) -> anyhow::Result<()> {
+    let readers = &diagnosis.reader_status.firmware_readers;
+    let mut writer = csv::WriterBuilder::new()
+        .quote_style(csv::QuoteStyle::Always)
+        .from_writer(out);
+    writer.write_record([
+        "uuid",
+        "mode",
+        "firmware_version",
+        "bus",
+        "address",
+        "applets",
+        "reachable",
+    ])?;
+    for logical_device in &diagnosis.logical_devices {
+        let reader = logical_device.readers.first().map(|i| &readers[*i]);
+        let usb_address = logical_device
+            .devices
+            .iter()
+            .find_map(|i| match diagnosis.devices[*i] {
+                Device::Firmware {
+                    bus: Some(bus),
+                    address: Some(address),
+                    ..
+                } => Some((bus, address)),
+                _ => None,
+            });
+        let applets = reader.map(|reader| {
+            reader
+                .applets
+                .iter()
+                .filter(|applet| applet.present)
+                .map(|applet| applet.name.as_str())
+                .collect::<Vec<_>>()
+                .join(";")
+        });
+        writer.write_record([
+            logical_device
+                .uuid
+                .map(|uuid| uuid.to_string())
+                .unwrap_or_default(),
+            logical_device.mode.to_string(),
+            reader
+                .and_then(|reader| reader.version)
+                .map(|version| version.to_string())
+                .unwrap_or_default(),
+            usb_address
+                .map(|(bus, _)| bus.to_string())
+                .unwrap_or_default(),
+            usb_address
+                .map(|(_, address)| address.to_string())
+                .unwrap_or_default(),
+            applets.unwrap_or_default(),
+            reader.is_some().to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn find_firmware_reader(
+    options: &ProbeOptions,
+    device: DeviceSelector,
+) -> anyhow::Result<FirmwareReader> {
+    let ctx = establish_pcsc_context()?;
+    let reader_status = get_reader_status(&ctx, options)?;
+    device.find(&reader_status.firmware_readers).cloned()
+}
+
+fn print_version(args: &Args, device: DeviceSelector) -> anyhow::Result<()> {
+    let options = ProbeOptions::new(args, false, vec![Applet::new(AID_ADMIN)])?;
+    let reader = find_firmware_reader(&options, device)?;
+    let version = reader.version.with_context(|| {
+        if reader.legacy {
+            format!(
+                "The firmware of the device with the uuid {} is too old to report its version",
+                reader.uuid
+            )
+        } else {
+            format!(
+                "Failed to query the firmware version of the device with the uuid {}",
+                reader.uuid
+            )
+        }
+    })?;
+    println!("{}", version);
+    Ok(())
+}
+
+/// Asks the user to confirm a state-changing action unless --assume-yes is set.
+fn confirm(args: &Args, question: &str) -> anyhow::Result<()> {
+    if args.assume_yes {
+        return Ok(());
+    }
+    anyhow::ensure!(
+        io::stdin().is_terminal(),
+        "Confirmation required, use --assume-yes if stdin is not a terminal"
+    );
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .context("Failed to read the confirmation")?;
+    anyhow::ensure!(
+        matches!(line.trim(), "y" | "Y" | "yes"),
+        "Aborted by the user"
+    );
+    Ok(())
+}
+
+fn reset(args: &Args, device: DeviceSelector, unpower: bool) -> anyhow::Result<()> {
+    let options = ProbeOptions::new(args, false, vec![Applet::new(AID_ADMIN)])?;
+    let reader = find_firmware_reader(&options, device)?;
+    confirm(
+        args,
+        &format!("Reset the device with the uuid {}?", reader.uuid),
+    )?;
+    let atr = reset_reader(&reader.name, unpower)?;
+    println!("{}", format_hex(&atr));
+    Ok(())
+}
+
+fn admin_raw(
+    args: &Args,
+    device: DeviceSelector,
+    ins: u8,
+    p1: u8,
+    p2: u8,
+    data: &[u8],
+    le: Option<u8>,
+) -> anyhow::Result<()> {
+    let options = ProbeOptions::new(args, false, vec![Applet::new(AID_ADMIN)])?;
+    let reader = find_firmware_reader(&options, device)?;
+    let (response, (sw1, sw2)) = admin_raw_transmit(&reader.name, ins, p1, p2, data, le)?;
+    println!("{}", format_hex(&response));
+    println!("{:02X}{:02X}", sw1, sw2);
+    Ok(())
+}
+
+#[cfg(feature = "ccid")]
+fn admin_raw_transmit(
+    name: &ffi::CStr,
+    ins: u8,
+    p1: u8,
+    p2: u8,
+    data: &[u8],
+    le: Option<u8>,
+) -> anyhow::Result<(Vec<u8>, (u8, u8))> {
+    let ctx = establish_pcsc_context()?;
+    let mut card = ctx
+        .connect(name, pcsc::ShareMode::Shared, pcsc::Protocols::T1)
+        .map_err(|err| pcsc_error_context(err, "Failed to connect to smartcard reader"))?;
+    let tx = card
+        .transaction()
+        .map_err(|err| pcsc_error_context(err, "Failed to start smartcard transaction"))?;
+    admin_raw_exchange(&tx, ins, p1, p2, data, le)
+}
+
+#[cfg(not(feature = "ccid"))]
+fn admin_raw_transmit(
+    _name: &ffi::CStr,
+    _ins: u8,
+    _p1: u8,
+    _p2: u8,
+    _data: &[u8],
+    _le: Option<u8>,
+) -> anyhow::Result<(Vec<u8>, (u8, u8))> {
+    anyhow::bail!(CCID_UNSUPPORTED)
+}
+
+#[cfg(feature = "ccid")]
+/// Selects the admin applet and sends the command.  Returns the response data and the status
+/// word.  The response data is always empty if the status word is not 9000.
+fn admin_raw_exchange(
+    tx: &dyn CardChannel,
+    ins: u8,
+    p1: u8,
+    p2: u8,
+    data: &[u8],
+    le: Option<u8>,
+) -> anyhow::Result<(Vec<u8>, (u8, u8))> {
+    ccid_select(tx, AID_ADMIN)?;
+    match ccid_transmit(tx, ins, p1, p2, data, le) {
+        Ok(response) => Ok((response, (0x90, 0x00))),
+        Err(err) => match err.downcast_ref::<CcidStatusError>() {
+            Some(status) => Ok((Vec::new(), (status.sw1, status.sw2))),
+            None => Err(err),
+        },
+    }
+}
+
+/// Resets or unpowers the card of the given reader and returns the new ATR.
+#[cfg(feature = "ccid")]
+fn reset_reader(name: &ffi::CStr, unpower: bool) -> anyhow::Result<Vec<u8>> {
+    let ctx = establish_pcsc_context()?;
+    let mut card = ctx
+        .connect(name, pcsc::ShareMode::Shared, pcsc::Protocols::T1)
+        .context("Failed to connect to smartcard reader")?;
+    let disposition = if unpower {
+        pcsc::Disposition::UnpowerCard
+    } else {
+        pcsc::Disposition::ResetCard
+    };
+    card.reconnect(pcsc::ShareMode::Shared, pcsc::Protocols::T1, disposition)
+        .context("Failed to reset card")?;
+    let status = card
+        .status2_owned()
+        .context("Failed to query card status")?;
+    Ok(status.atr().to_owned())
+}
+
+#[cfg(not(feature = "ccid"))]
+fn reset_reader(_name: &ffi::CStr, _unpower: bool) -> anyhow::Result<Vec<u8>> {
+    anyhow::bail!(CCID_UNSUPPORTED)
+}
+
+#[cfg(feature = "ccid")]
+fn check_pcsc() -> Check {
+    let ctx = match pcsc::Context::establish(pcsc::Scope::System) {
+        Ok(ctx) => ctx,
+        Err(err) => {
+            return Check::fail(
+                "pcsc",
+                format!("Failed to establish pcsc context: {}", err),
+                PCSC_REMEDIATION,
+            )
+        }
+    };
+    let readers = match ctx.list_readers_owned() {
+        Err(pcsc::Error::NoReadersAvailable) => Vec::new(),
+        Ok(readers) => readers,
+        Err(err) => {
+            return Check::fail(
+                "pcsc",
+                format!("Failed to list pcsc readers: {}", err),
+                PCSC_REMEDIATION,
+            )
+        }
+    };
+    if readers.iter().any(|reader| is_firmware_reader_name(reader)) {
+        Check::pass("pcsc", "Found a smartcard reader of a firmware device")
+    } else {
+        Check::warn(
+            "pcsc",
+            format!(
+                "pcsc is available, but none of the {} smartcard reader(s) belongs to a firmware device",
+                readers.len()
+            ),
+            "Connect a Nitrokey 3 and check that the CCID driver supports it.",
+        )
+    }
+}
+
+#[cfg(not(feature = "ccid"))]
+fn check_pcsc() -> Check {
+    Check::warn(
+        "pcsc",
+        CCID_UNSUPPORTED,
+        "Use a build with the ccid feature to check the firmware readers.",
+    )
+}
+
+fn check_libusb() -> Check {
+    let result = libusb::Context::new()
+        .context("Failed to establish libusb context")
+        .and_then(|ctx| {
+            ctx.devices()
+                .map(|devices| devices.iter().count())
+                .context("Failed to list USB devices")
+        });
+    match result {
+        Ok(count) => Check::pass("libusb", format!("libusb lists {} USB device(s)", count)),
+        Err(err) => Check::warn(
+            "libusb",
+            format!("{:#}", err),
+            "Check that libusb is installed and that USB devices are accessible, or use --usb-backend hidapi.",
+        ),
+    }
+}
+
+fn check_hidapi() -> Check {
+    match hidapi::HidApi::new() {
+        Ok(api) => Check::pass(
+            "hidapi",
+            format!("hidapi lists {} HID device(s)", api.device_list().count()),
+        ),
+        Err(err) => Check::warn(
+            "hidapi",
+            format!("Failed to initialize hidapi: {}", err),
+            "Check that HID devices are accessible.  Bootloader devices can only be found with hidapi.",
+        ),
+    }
+}
+
+fn check_devices() -> Check {
+    match find_devices(UsbBackend::Auto, None) {
+        Ok(UsbDevices { devices, .. }) if devices.is_empty() => Check::warn(
+            "devices",
+            "No supported devices found",
+            "Connect a Nitrokey 3 and check the USB connection.",
+        ),
+        Ok(UsbDevices {
+            backend, devices, ..
+        }) => Check::pass(
+            "devices",
+            format!("Found {} device(s) with {}", devices.len(), backend),
+        ),
+        Err(err) => Check::fail(
+            "devices",
+            format!("{:#}", err),
+            "Check that USB devices are accessible.",
+        ),
+    }
+}
+
+/// Returns the first udev rules file that mentions the firmware devices.
+#[cfg(target_os = "linux")]
+fn find_udev_rules() -> Option<path::PathBuf> {
+    let pid = format!("{:04x}", PID_FIRMWARE);
+    UDEV_RULES_DIRS
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() == Some(ffi::OsStr::new("rules")))
+        .find(|path| {
+            fs::read_to_string(path)
+                .map(|content| content.to_lowercase().contains(&pid))
+                .unwrap_or_default()
+        })
+}
+
+/// Returns true if the process is probably not allowed to access the USB devices, that is if
+/// it does not run as root and there are no udev rules for the firmware devices.
+#[cfg(target_os = "linux")]
+fn lacks_usb_permissions() -> bool {
+    use std::os::unix::fs::MetadataExt as _;
+
+    let root = fs::metadata("/proc/self").is_ok_and(|metadata| metadata.uid() == 0);
+    !root && find_udev_rules().is_none()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lacks_usb_permissions() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn check_udev_rules() -> Check {
+    match find_udev_rules() {
+        Some(path) => Check::pass(
+            "udev",
+            format!("udev rules for firmware devices found in {}", path.display()),
+        ),
+        None => Check::warn(
+            "udev",
+            "No udev rules for firmware devices found",
+            "Install the Nitrokey udev rules (41-nitrokey.rules) and reload them with `udevadm control --reload-rules`.",
+        ),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn check_info_plist() -> Check {
+    let pid = format!("0x{:04x}", PID_FIRMWARE);
+    match fs::read_to_string(CCID_INFO_PLIST) {
+        Ok(content) if content.to_lowercase().contains(&pid) => {
+            Check::pass("info_plist", "The CCID driver supports firmware devices")
+        }
+        Ok(_) => Check::warn(
+            "info_plist",
+            format!("{} does not list firmware devices", CCID_INFO_PLIST),
+            "Install the updated Info.plist file for the CCID driver.",
+        ),
+        Err(err) => Check::warn(
+            "info_plist",
+            format!("Failed to read {}: {}", CCID_INFO_PLIST, err),
+            "Install the updated Info.plist file for the CCID driver.",
+        ),
+    }
+}
+
+fn run_checks() -> Vec<Check> {
+    let mut checks = vec![check_pcsc(), check_libusb(), check_hidapi()];
+    #[cfg(target_os = "linux")]
+    checks.push(check_udev_rules());
+    #[cfg(target_os = "macos")]
+    checks.push(check_info_plist());
+    checks.push(check_devices());
+    checks
+}
+
+fn print_checks(checks: &[Check]) {
+    for check in checks {
+        println!("[{}] {}: {}", check.status, check.name, check.message);
+        if let Some(remediation) = check.remediation {
+            println!("       {}", remediation);
+        }
+    }
+}
+
+fn doctor(args: &Args, format: SubcommandFormat) -> anyhow::Result<()> {
+    let checks = run_checks();
+    let worst = checks
+        .iter()
+        .map(|check| check.status)
+        .max()
+        .unwrap_or(CheckStatus::Pass);
+
+    if !args.quiet {
+        match format {
+            SubcommandFormat::Text => print_checks(&checks),
+            SubcommandFormat::Json => {
+                let stdout = io::stdout();
+                let mut out = stdout.lock();
+                serde_json::to_writer_pretty(&mut out, &checks)
+                    .context("Failed to serialize checks")?;
+                writeln!(out)?;
+            }
+        }
+    }
+
+    exit(worst.exit_code());
+}
+
+/// Runs the checks for the update-ready subcommand on the given reader.
+fn check_update_ready(reader_status: &ReaderStatus, reader: &FirmwareReader) -> Vec<Check> {
+    let mut checks = vec![Check::pass(
+        "admin",
+        format!(
+            "The admin applet is reachable via {}",
+            reader.name.to_string_lossy()
+        ),
+    )];
+    checks.push(match reader.version {
+        Some(version) => Check::pass("version", format!("Firmware version {}", version)),
+        None if reader.legacy => Check::warn(
+            "version",
+            "Legacy firmware (version unavailable)",
+            "Update the device with the Nitrokey app.",
+        ),
+        None => Check::fail(
+            "version",
+            "Failed to query the firmware version",
+            "Update the device with the Nitrokey app or reconnect it and try again.",
+        ),
+    });
+    checks.push(if reader.provisioner {
+        Check::fail(
+            "provisioner",
+            "The device runs the provisioner firmware",
+            "Provisioner devices are updated as part of the provisioning process.",
+        )
+    } else {
+        Check::pass("provisioner", "The device runs the regular firmware")
+    });
+    checks.push(if reader.needs_factory_reset {
+        Check::fail(
+            "status",
+            format!(
+                "The device is in an inconsistent state (init status {:#04x})",
+                reader.init_status.unwrap_or_default()
+            ),
+            "Perform a factory reset before the update.",
+        )
+    } else {
+        Check::pass("status", "The device status is ok")
+    });
+    checks.push(match reader.secure_boot {
+        Some(true) => Check::pass(
+            "secure_boot",
+            "Secure boot is enabled, only signed firmware is accepted",
+        ),
+        Some(false) => Check::warn(
+            "secure_boot",
+            "Secure boot is disabled",
+            "Only use firmware images that match the device configuration.",
+        ),
+        None => Check::warn(
+            "secure_boot",
+            "Failed to query the secure boot status",
+            "Update tools may not be able to determine which firmware image to use.",
+        ),
+    });
+    checks.push(match reader.contactless {
+        Some(false) => Check::pass("power", "The device is connected via USB"),
+        Some(true) => Check::warn(
+            "power",
+            "The device is connected via NFC",
+            "Connect the device via USB so that it is not powered off during the update.",
+        ),
+        None => Check::warn(
+            "power",
+            "Failed to determine whether the device is connected via USB or NFC",
+            "Make sure that the device is connected via USB during the update.",
+        ),
+    });
+    checks.push(if reader_status.firmware_readers.len() > 1 {
+        Check::warn(
+            "devices",
+            format!(
+                "{} firmware devices are connected",
+                reader_status.firmware_readers.len()
+            ),
+            "Disconnect all other devices so that the update tool selects the right device.",
+        )
+    } else {
+        Check::pass("devices", "Only one firmware device is connected")
+    });
+    checks
+}
+
+#[derive(Debug, serde::Serialize)]
+struct UpdateReadiness {
+    uuid: Uuid,
+    ready: bool,
+    checks: Vec<Check>,
+}
+
+fn update_ready(
+    args: &Args,
+    format: SubcommandFormat,
+    device: DeviceSelector,
+) -> anyhow::Result<()> {
+    let options = ProbeOptions::new(args, false, AidSet::Builtin.applets(&[]))?;
+    let ctx = establish_pcsc_context()?;
+    let reader_status = get_reader_status(&ctx, &options)?;
+    let reader = device.find(&reader_status.firmware_readers)?;
+    let uuid = reader.uuid;
+    let checks = check_update_ready(&reader_status, reader);
+    let readiness = UpdateReadiness {
+        uuid,
+        ready: checks.iter().all(|check| check.status != CheckStatus::Fail),
+        checks,
+    };
+
+    if !args.quiet {
+        match format {
+            SubcommandFormat::Text => {
+                print_checks(&readiness.checks);
+                println!();
+                if readiness.ready {
+                    println!("The device with the uuid {} is ready for an update.", uuid);
+                } else {
+                    println!(
+                        "The device with the uuid {} is not ready for an update.",
+                        uuid
+                    );
+                }
+            }
+            SubcommandFormat::Json => {
+                let stdout = io::stdout();
+                let mut out = stdout.lock();
+                serde_json::to_writer_pretty(&mut out, &readiness)
+                    .context("Failed to serialize update readiness")?;
+                writeln!(out)?;
+            }
+        }
+    }
+
+    anyhow::ensure!(readiness.ready, "Device is not ready for an update");
+    Ok(())
+}
+
+fn bench(args: &Args, format: SubcommandFormat, duration: time::Duration) -> anyhow::Result<()> {
+    let options = ProbeOptions::new(args, false, probe_applets(args))?;
+    let start = time::Instant::now();
+    let mut latencies = Vec::new();
+    while start.elapsed() < duration {
+        let iteration_start = time::Instant::now();
+        find_devices(args.usb_backend, None)?;
+        if args.bench_ccid {
+            let ctx = establish_pcsc_context()?;
+            get_reader_status(&ctx, &options)?;
+        }
+        latencies.push(iteration_start.elapsed());
+    }
+    let result = BenchResult::new(start.elapsed(), latencies);
+
+    if !args.quiet {
+        match format {
+            SubcommandFormat::Text => {
+                println!("Iterations: {}", result.iterations);
+                println!("Scans per second: {:.2}", result.scans_per_second);
+                println!(
+                    "Latency: mean {:.2} ms, p50 {:.2} ms, p99 {:.2} ms",
+                    result.mean_ms, result.p50_ms, result.p99_ms
+                );
+            }
+            SubcommandFormat::Json => {
+                let stdout = io::stdout();
+                let mut out = stdout.lock();
+                serde_json::to_writer_pretty(&mut out, &result)
+                    .context("Failed to serialize benchmark result")?;
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Handles one HTTP request: only GET /diagnose is supported.
+fn handle_http_request(
+    stream: &mut std::net::TcpStream,
+    args: &Args,
+    options: &ProbeOptions,
+    expected_uuids: &BTreeSet<Uuid>,
+) -> anyhow::Result<()> {
+    use std::io::BufRead as _;
+
+    // A client that does not send a complete request must not block the server
+    stream.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not needed, but have to be consumed before responding
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/diagnose")) => {
+            match run_diagnosis(args, options, expected_uuids, None) {
+                Ok((diagnosis, _)) => ("200 OK", serde_json::to_string_pretty(&diagnosis)?),
+                Err(err) => (
+                    "500 Internal Server Error",
+                    serde_json::json!({ "error": format!("{:#}", err) }).to_string(),
+                ),
+            }
+        }
+        (Some("GET"), Some(_)) => (
+            "404 Not Found",
+            serde_json::json!({ "error": "Not found" }).to_string(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            serde_json::json!({ "error": "Method not allowed" }).to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// A known applet and the AID sets that contain it
+#[derive(Debug, serde::Serialize)]
+struct KnownApplet {
+    name: &'static str,
+    aid: String,
+    aid_sets: Vec<&'static str>,
+}
+
+/// Returns the JSON Schema of the diagnosis with schema_version fixed to SCHEMA_VERSION.
+fn diagnosis_schema() -> schemars::schema::RootSchema {
+    let mut schema = schemars::schema_for!(Diagnosis);
+    let version = schema.schema.object().properties.get_mut("schema_version");
+    if let Some(schemars::schema::Schema::Object(version)) = version {
+        version.const_value = Some(SCHEMA_VERSION.into());
+    }
+    schema
+}
+
+fn print_schema() -> anyhow::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    serde_json::to_writer_pretty(&mut out, &diagnosis_schema())
+        .context("Failed to serialize schema")?;
+    writeln!(out)?;
+    Ok(())
+}
+
+fn list_aids(args: &Args, format: SubcommandFormat) -> anyhow::Result<()> {
+    let builtin = AidSet::Builtin.applets(&[]);
+    let applets: Vec<_> = KNOWN_APPLETS
+        .iter()
+        .map(|(name, aid)| {
+            let mut aid_sets = Vec::new();
+            if builtin.iter().any(|applet| applet.aid == *aid) {
+                aid_sets.push("builtin");
+            }
+            aid_sets.push("full");
+            KnownApplet {
+                name,
+                aid: format_hex(aid),
+                aid_sets,
+            }
+        })
+        .collect();
+    if args.quiet {
+        return Ok(());
+    }
+    match format {
+        SubcommandFormat::Text => {
+            for applet in &applets {
+                println!(
+                    "{:<12} {:<18} ({})",
+                    applet.name,
+                    applet.aid,
+                    applet.aid_sets.join(", ")
+                );
+            }
+        }
+        SubcommandFormat::Json => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            serde_json::to_writer_pretty(&mut out, &applets)
+                .context("Failed to serialize applets")?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// The components of a UUID shown by the decode-uuid subcommand
+#[derive(Debug, serde::Serialize)]
+struct DecodedUuid {
+    raw: String,
+    canonical: String,
+    /// The UUID bytes in groups of four, e. g. to compare them with a chip ID
+    words: Vec<String>,
+    interpretation: &'static str,
+}
+
+impl DecodedUuid {
+    fn new(uuid: Uuid) -> Self {
+        let bytes = uuid.0.to_be_bytes();
+        let hex = format_hex(&bytes).to_lowercase();
+        Self {
+            canonical: format!(
+                "{}-{}-{}-{}-{}",
+                &hex[..8],
+                &hex[8..12],
+                &hex[12..16],
+                &hex[16..20],
+                &hex[20..]
+            ),
+            words: bytes
+                .chunks(4)
+                .map(|word| format_hex(word).to_lowercase())
+                .collect(),
+            interpretation: interpret_uuid(uuid),
+            raw: hex,
+        }
+    }
+}
+
+/// Describes the probable origin of a UUID.  The firmware uses the unique ID of the chip,
+/// which has 128 bits on the LPC55 and 64 bits on the nRF52, padded with zeros.
+fn interpret_uuid(uuid: Uuid) -> &'static str {
+    if uuid.is_unset() {
+        "unset or corrupt, the device may need to be re-provisioned"
+    } else if uuid.0 as u64 == 0 {
+        "64-bit chip ID padded with zeros, probably an nRF52 device (Nitrokey 3A Mini)"
+    } else {
+        "128-bit chip ID, probably an LPC55 device (Nitrokey 3A/C NFC)"
+    }
+}
+
+fn decode_uuid(args: &Args, format: SubcommandFormat, uuid: Uuid) -> anyhow::Result<()> {
+    let decoded = DecodedUuid::new(uuid);
+    if args.quiet {
+        return Ok(());
+    }
+    match format {
+        SubcommandFormat::Text => {
+            println!("raw:            {}", decoded.raw);
+            println!("canonical:      {}", decoded.canonical);
+            println!("words:          {}", decoded.words.join(" "));
+            println!("interpretation: {}", decoded.interpretation);
+        }
+        SubcommandFormat::Json => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            serde_json::to_writer_pretty(&mut out, &decoded).context("Failed to serialize UUID")?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// The parts of a saved diagnosis that are compared by the diff subcommand.  Other fields
+/// are ignored, and missing lists are treated as empty, e. g. with --omit-empty.
+#[derive(Debug, serde::Deserialize)]
+struct SavedDiagnosis {
+    #[serde(default)]
+    devices: Vec<Device>,
+    #[serde(default)]
+    firmware_readers: Vec<SavedFirmwareReader>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SavedFirmwareReader {
+    uuid: Uuid,
+    version: Option<String>,
+    #[serde(default)]
+    applets: BTreeMap<String, SavedAppletStatus>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SavedAppletStatus {
+    present: bool,
+}
+
+impl SavedDiagnosis {
+    /// Parses a saved diagnosis.  Reports saved with --redact cannot be compared as the
+    /// masked UUIDs do not identify the devices.
+    fn from_value(value: serde_json::Value) -> anyhow::Result<Self> {
+        let is_redacted = |entry: &serde_json::Value| {
+            entry["uuid"]
+                .as_str()
+                .is_some_and(|uuid| uuid.contains('*'))
+        };
+        let redacted = ["devices", "firmware_readers"]
+            .iter()
+            .filter_map(|key| value[*key].as_array())
+            .flatten()
+            .any(is_redacted);
+        anyhow::ensure!(
+            !redacted,
+            "Cannot diff redacted reports.  Save the reports without --redact to compare them."
+        );
+        serde_json::from_value(value).context("Unexpected diagnosis format")
+    }
+
+    /// Returns the UUIDs of the bootloader devices and the firmware readers.
+    fn uuids(&self) -> BTreeSet<Uuid> {
+        self.devices
+            .iter()
+            .filter_map(|device| match device {
+                Device::Bootloader { uuid, .. } => Some(*uuid),
+                Device::Recovery { .. } | Device::Firmware { .. } => None,
+            })
+            .chain(self.firmware_readers.iter().map(|reader| reader.uuid))
+            .collect()
+    }
+}
+
+/// The changes between two saved diagnoses
+#[derive(Debug, Default, serde::Serialize)]
+struct DiagnosisDiff {
+    added: Vec<Uuid>,
+    removed: Vec<Uuid>,
+    version_changes: Vec<VersionChange>,
+    applet_changes: Vec<AppletChange>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct VersionChange {
+    uuid: Uuid,
+    old: Option<String>,
+    new: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct AppletChange {
+    uuid: Uuid,
+    gained: Vec<String>,
+    lost: Vec<String>,
+}
+
+impl DiagnosisDiff {
+    /// Compares the devices by UUID.  Versions and applets are only compared for devices that
+    /// have a firmware reader in both diagnoses.
+    fn new(old: &SavedDiagnosis, new: &SavedDiagnosis) -> Self {
+        let (old_uuids, new_uuids) = (old.uuids(), new.uuids());
+        let mut diff = Self {
+            added: new_uuids.difference(&old_uuids).copied().collect(),
+            removed: old_uuids.difference(&new_uuids).copied().collect(),
+            ..Default::default()
+        };
+        for new_reader in &new.firmware_readers {
+            let old_reader = match old
+                .firmware_readers
+                .iter()
+                .find(|reader| reader.uuid == new_reader.uuid)
+            {
+                Some(reader) => reader,
+                None => continue,
+            };
+            if old_reader.version != new_reader.version {
+                diff.version_changes.push(VersionChange {
+                    uuid: new_reader.uuid,
+                    old: old_reader.version.clone(),
+                    new: new_reader.version.clone(),
+                });
+            }
+            let present = |reader: &SavedFirmwareReader| -> BTreeSet<String> {
+                reader
+                    .applets
+                    .iter()
+                    .filter(|(_, applet)| applet.present)
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            };
+            let (old_applets, new_applets) = (present(old_reader), present(new_reader));
+            if old_applets != new_applets {
+                diff.applet_changes.push(AppletChange {
+                    uuid: new_reader.uuid,
+                    gained: new_applets.difference(&old_applets).cloned().collect(),
+                    lost: old_applets.difference(&new_applets).cloned().collect(),
+                });
+            }
+        }
+        diff
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.version_changes.is_empty()
+            && self.applet_changes.is_empty()
+    }
+}
+
+fn print_diff(out: &mut dyn io::Write, diff: &DiagnosisDiff) -> io::Result<()> {
+    if diff.is_empty() {
+        return writeln!(out, "No changes");
+    }
+    for uuid in &diff.added {
+        writeln!(out, "+ uuid {}", uuid)?;
+    }
+    for uuid in &diff.removed {
+        writeln!(out, "- uuid {}", uuid)?;
+    }
+    let unknown = "unknown";
+    for change in &diff.version_changes {
+        writeln!(
+            out,
+            "~ uuid {}: firmware {} -> {}",
+            change.uuid,
+            change.old.as_deref().unwrap_or(unknown),
+            change.new.as_deref().unwrap_or(unknown)
+        )?;
+    }
+    for change in &diff.applet_changes {
+        for applet in &change.gained {
+            writeln!(out, "~ uuid {}: applet {} gained", change.uuid, applet)?;
+        }
+        for applet in &change.lost {
+            writeln!(out, "~ uuid {}: applet {} lost", change.uuid, applet)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_saved_diagnosis(path: &path::Path) -> anyhow::Result<SavedDiagnosis> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read diagnosis {}", path.display()))?;
+    serde_json::from_str(&content)
+        .map_err(anyhow::Error::from)
+        .and_then(SavedDiagnosis::from_value)
+        .with_context(|| format!("Failed to parse diagnosis {}", path.display()))
+}
+
+fn diff(
+    args: &Args,
+    format: SubcommandFormat,
+    old: &path::Path,
+    new: &path::Path,
+) -> anyhow::Result<()> {
+    let diff = DiagnosisDiff::new(&read_saved_diagnosis(old)?, &read_saved_diagnosis(new)?);
+    if args.quiet {
+        return Ok(());
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match format {
+        SubcommandFormat::Text => print_diff(&mut out, &diff)?,
+        SubcommandFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &diff).context("Failed to serialize diff")?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+fn serve(args: &Args, bind: std::net::IpAddr, port: u16) -> anyhow::Result<()> {
+    let expected_uuids = get_expected_uuids(args)?;
+    anyhow::ensure!(
+        args.aid_set == AidSet::Custom || args.select_aids.is_empty(),
+        "--select-aid can only be used with --aid-set custom"
+    );
+    let options = ProbeOptions::new(args, false, probe_applets(args))?;
+    let listener = std::net::TcpListener::bind((bind, port))
+        .with_context(|| format!("Failed to listen on {}:{}", bind, port))?;
+    if !args.quiet {
+        eprintln!(
+            "Serving the diagnosis at http://{}/diagnose",
+            listener.local_addr()?
+        );
+    }
+    // Only one request is handled at a time as hidapi and the readers cannot be shared
+    for stream in listener.incoming() {
+        let result = stream
+            .context("Failed to accept connection")
+            .and_then(|mut stream| {
+                handle_http_request(&mut stream, args, &options, &expected_uuids)
+            });
+        if let Err(err) = result {
+            log!("HTTP request failed: {:#}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if bootloader devices, but no firmware devices or readers were found, i. e.
+/// if the devices have to be booted into the firmware.
+fn only_bootloader_devices(diagnosis: &Diagnosis) -> bool {
+    let bootloader = diagnosis
+        .devices
+        .iter()
+        .any(|device| matches!(device, Device::Bootloader { .. }));
+    let firmware = diagnosis
+        .devices
+        .iter()
+        .any(|device| matches!(device, Device::Firmware { .. }));
+    bootloader && !firmware && diagnosis.reader_status.firmware_readers.is_empty()
+}
+
+/// The exit code for --check-only.  Exit code 2 for other errors is set in main.
+fn check_exit_code(diagnosis: &Diagnosis, expected_uuids: &BTreeSet<Uuid>) -> i32 {
+    let missing = diagnosis
+        .uuid_check
+        .as_ref()
+        .is_some_and(|uuid_check| !uuid_check.missing.is_empty());
+    let warnings = diagnosis.warnings.iter().any(|warning| {
+        expected_uuids.is_empty()
+            || warning
+                .uuid()
+                .is_none_or(|uuid| expected_uuids.contains(&uuid))
+    });
+    let libusb_error = matches!(diagnosis.libusb, LibusbStatus::Error { .. });
+    if missing {
+        2
+    } else if libusb_error {
+        3
+    } else if only_bootloader_devices(diagnosis) {
+        EXIT_BOOTLOADER_ONLY
+    } else if warnings {
+        1
+    } else {
+        0
+    }
+}
+
+fn get_expected_uuids(args: &Args) -> anyhow::Result<BTreeSet<Uuid>> {
+    let mut expected_uuids: BTreeSet<_> = args.expect_uuids.iter().copied().collect();
+    if let Some(path) = &args.expect_uuids_file {
+        expected_uuids.extend(read_uuids_file(path)?);
+    }
+    Ok(expected_uuids)
+}
+
+/// Runs the diagnosis and returns it together with all UUIDs that were found.
+fn run_diagnosis(
+    args: &Args,
+    options: &ProbeOptions,
+    expected_uuids: &BTreeSet<Uuid>,
+    state_file: Option<&path::Path>,
+) -> anyhow::Result<(Diagnosis, BTreeSet<Uuid>)> {
+    // Builds without CCID support always continue with the USB results
+    let ccid_unavailable = |err| {
+        if args.ccid_best_effort || !cfg!(feature = "ccid") {
+            Ok((ReaderStatus::default(), Some(err)))
+        } else {
+            Err(err)
+        }
+    };
+    let (usb_result, ccid_result) = if let Some(path) = &args.replay {
+        let recording = read_recording(path)?;
+        let usb = recording
+            .usb
+            .clone()
+            .context("The recording does not contain the USB devices");
+        let ccid = match &recording.ccid_error {
+            Some(error) => ccid_unavailable(anyhow::anyhow!("{}", error)),
+            None => {
+                replay_reader_status(&recording, options).map(|reader_status| (reader_status, None))
+            }
+        };
+        (usb, ccid)
+    } else {
+        // USB and CCID enumeration use independent subsystems, so they can run
+        // concurrently.  Both results are joined before anything is printed.
+        thread::scope(|scope| {
+            let usb = scope.spawn(|| find_devices(args.usb_backend, DescriptorDump::new(args)));
+            let ccid = match establish_pcsc_context() {
+                Ok(ctx) => {
+                    get_reader_status(&ctx, options).map(|reader_status| (reader_status, None))
+                }
+                Err(err) => ccid_unavailable(err),
+            };
+            let usb = usb.join().unwrap_or_else(|err| panic::resume_unwind(err));
+            (usb, ccid)
+        })
+    };
+    if let Some(path) = &args.record {
+        if let Some(mut recording) = recording() {
+            recording.usb = usb_result.as_ref().ok().cloned();
+            recording.ccid_error = match &ccid_result {
+                Ok((_, None)) => None,
+                Ok((_, Some(err))) | Err(err) => Some(format!("{:#}", err)),
+            };
+        }
+        write_recording(path)?;
+    }
+    let (usb_devices, (mut reader_status, pcsc_error)) = match (usb_result, ccid_result) {
+        (Ok(usb_devices), Ok(ccid)) => (usb_devices, ccid),
+        (Err(err), Ok(_)) | (Ok(_), Err(err)) => return Err(err),
+        (Err(usb_err), Err(ccid_err)) => anyhow::bail!(
+            "USB and CCID enumeration failed\n- USB: {:#}\n- CCID: {:#}",
+            usb_err,
+            ccid_err
+        ),
+    };
+    let UsbDevices {
+        backend: usb_backend,
+        libusb,
+        devices,
+    } = usb_devices;
+    let reader_device_count = devices
+        .iter()
+        .filter(|device| device.expects_reader())
+        .count();
+    reader_status.classify_stale_readers(&devices, usb_backend);
+    if args.strict && pcsc_error.is_none() {
+        anyhow::ensure!(
+            reader_device_count <= reader_status.firmware_readers.len(),
+            "Aborting because of --strict: {} firmware device(s) found, but only {} firmware \
+             reader(s) could be probed",
+            reader_device_count,
+            reader_status.firmware_readers.len()
+        );
+    }
+
+    let found_uuids: BTreeSet<_> = devices
+        .iter()
+        .filter_map(|device| match device {
+            Device::Bootloader { uuid, .. } => Some(*uuid),
+            Device::Recovery { .. } | Device::Firmware { .. } => None,
+        })
+        .chain(
+            reader_status
+                .firmware_readers
+                .iter()
+                .map(|reader| reader.uuid),
+        )
+        .collect();
+    let uuid_check = if expected_uuids.is_empty() {
+        None
+    } else {
+        Some(UuidCheck::new(expected_uuids, &found_uuids))
+    };
+    let state_delta =
+        state_file.map(|path| StateDelta::new(&read_state_file(path).uuids, &found_uuids));
+
+    let mut warnings = get_warnings(&devices, &reader_status, args.allow_provisioner);
+    let environment = Environment::new(pcsc_error.is_none());
+    if let Some(err) = pcsc_error {
+        warnings.push(Warning::PcscUnavailable {
+            error: format!("{:#}", err),
+        });
+    }
+    // The HID enumeration for the bootloader devices has worked at this point, so libusb
+    // errors and an empty device list point to a libusb setup problem.
+    match &libusb {
+        LibusbStatus::Error { error } => warnings.push(Warning::LibusbError {
+            error: error.clone(),
+        }),
+        LibusbStatus::Ok { device_count: 0 } => warnings.push(Warning::LibusbNoDevices),
+        LibusbStatus::Ok { .. } | LibusbStatus::NotUsed => {}
+    }
+    let logical_devices = unify_devices(&devices, &reader_status.firmware_readers);
+    for logical_device in &logical_devices {
+        if let (Some(uuid), Some(versions)) = (logical_device.uuid, &logical_device.versions) {
+            if versions.compatibility == Compatibility::Incompatible {
+                warnings.push(Warning::IncompatibleVersions {
+                    uuid,
+                    bootloader: versions.bootloader.clone(),
+                    firmware: versions.firmware,
+                });
+            }
+        }
+    }
+    warnings.retain(|warning| warning.severity() >= args.min_severity);
+    let applet_summary = summarize_applets(&options.applets, &reader_status.firmware_readers);
+    let diagnosis = Diagnosis {
+        schema_version: SCHEMA_VERSION,
+        generated_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        hostname: gethostname::gethostname().into_string().ok(),
+        environment,
+        usb_backend,
+        libusb,
+        devices,
+        reader_state: reader_status.state(),
+        reader_status,
+        applet_summary,
+        uuid_check,
+        state_delta,
+        logical_devices,
+        warnings,
+        apdu_log: apdu_log().map(|mut log| std::mem::take(&mut log.entries)),
+    };
+    Ok((diagnosis, found_uuids))
+}
+
+/// Removes all null fields and empty arrays from the objects in a JSON value.
+fn omit_empty(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            *map = std::mem::take(map)
+                .into_iter()
+                .filter(|(_, value)| match value {
+                    serde_json::Value::Null => false,
+                    serde_json::Value::Array(values) => !values.is_empty(),
+                    _ => true,
+                })
+                .collect();
+            map.values_mut().for_each(omit_empty);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(omit_empty),
+        _ => {}
+    }
+}
+
+fn print_json(
+    out: &mut dyn io::Write,
+    diagnosis: &Diagnosis,
+    omit_empty_fields: bool,
+) -> anyhow::Result<()> {
+    if omit_empty_fields {
+        let mut value = serde_json::to_value(diagnosis).context("Failed to serialize diagnosis")?;
+        omit_empty(&mut value);
+        serde_json::to_writer_pretty(&mut *out, &value)
+    } else {
+        serde_json::to_writer_pretty(&mut *out, diagnosis)
+    }
+    .context("Failed to serialize diagnosis")?;
+    writeln!(out)?;
+    Ok(())
+}
+
+fn diagnose(args: &Args) -> anyhow::Result<()> {
+    let expected_uuids = get_expected_uuids(args)?;
+
+    let mut out: Box<dyn io::Write> = if args.quiet || args.check_only {
+        Box::new(io::sink())
+    } else {
+        Box::new(io::stdout())
+    };
+
+    anyhow::ensure!(
+        args.aid_set == AidSet::Custom || args.select_aids.is_empty(),
+        "--select-aid can only be used with --aid-set custom"
+    );
+    // Only show progress for interactive use so that piped output stays clean.  The log
+    // messages of --verbose would interfere with the progress line.
+    let progress = !args.quiet
+        && !args.verbose
+        && args.format == Format::Text
+        && io::stdout().is_terminal()
+        && io::stderr().is_terminal();
+    // The admin queries always run, so no further applets are needed for --oneline
+    let applets = if args.oneline {
+        Vec::new()
+    } else {
+        probe_applets(args)
+    };
+    let options = ProbeOptions::new(args, progress && !args.oneline, applets)?;
+    if !args.quiet
+        && !args.check_only
+        && !args.oneline
+        && args.replay.is_none()
+        && lacks_usb_permissions()
+    {
+        eprintln!(
+            "Note: Not running as root and no udev rules for firmware devices found, so the \
+             results may be incomplete.  Run `nk3-diagnose doctor` for details."
+        );
+    }
+    let (diagnosis, found_uuids) =
+        run_diagnosis(args, &options, &expected_uuids, args.state_file.as_deref())?;
+    // With a state file, the removal of the last device has to be reported.  The single
+    // line summary reports it as well.
+    anyhow::ensure!(
+        diagnosis.has_devices() || args.state_file.is_some() || args.oneline,
+        "No supported devices found"
+    );
+
+    if args.first_match {
+        let reader = diagnosis
+            .reader_status
+            .firmware_readers
+            .iter()
+            .find(|reader| !reader.uuid.is_unset())
+            .context("No reachable firmware device with a valid UUID found")?;
+        match args.format {
+            Format::Json => {
+                let mut value = serde_json::to_value(reader)?;
+                if args.omit_empty {
+                    omit_empty(&mut value);
+                }
+                serde_json::to_writer_pretty(&mut out, &value)?;
+                writeln!(out)?;
+            }
+            _ => writeln!(out, "{}", reader)?,
+        }
+        return Ok(());
+    }
+
+    match args.format {
+        _ if args.oneline => writeln!(out, "{}", oneline_summary(&diagnosis))?,
+        Format::Auto => unreachable!("--format auto is resolved in main"),
+        Format::Text => print_text(&mut out, &diagnosis, args.verbose)?,
+        Format::Json => print_json(&mut out, &diagnosis, args.omit_empty)?,
+        Format::Env => print_env(&mut out, &diagnosis)?,
+        Format::Prometheus => print_prometheus(&mut out, &diagnosis)?,
+        Format::Csv => print_csv(&mut out, &diagnosis)?,
+        Format::Ndjson => print_ndjson(&mut out, &diagnosis)?,
+    }
+    if let Some(path) = &args.also_json {
+        let mut file = fs::File::create(path)
+            .with_context(|| format!("Failed to create JSON file {}", path.display()))?;
+        print_json(&mut file, &diagnosis, args.omit_empty)
+            .with_context(|| format!("Failed to write JSON file {}", path.display()))?;
+    }
+
+    if let Some(path) = &args.state_file {
+        write_state_file(path, &State { uuids: found_uuids })?;
+    }
+
+    if args.check_only {
+        exit(check_exit_code(&diagnosis, &expected_uuids));
+    }
+
+    if let Some(uuid_check) = &diagnosis.uuid_check {
+        anyhow::ensure!(
+            uuid_check.missing.is_empty(),
+            "{} expected UUID(s) missing",
+            uuid_check.missing.len()
+        );
+    }
+    anyhow::ensure!(
+        !args.fail_on_warning || diagnosis.warnings.is_empty(),
+        "{} warning(s) emitted",
+        diagnosis.warnings.len()
+    );
+    if only_bootloader_devices(&diagnosis) {
+        exit(EXIT_BOOTLOADER_ONLY);
+    }
+
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    args.format = args.format.resolve();
+    UUID_FORMAT
+        .set(args.uuid_format)
+        .expect("UUID format is only set once");
+    REDACT_UUIDS
+        .set(args.redact)
+        .expect("UUID redaction is only set once");
+    let log_file = args
+        .log_file
+        .as_deref()
+        .and_then(|path| match fs::File::create(path) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(err) => {
+                eprintln!(
+                    "Failed to open log file {}, continuing without it: {}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        });
+    LOGGER
+        .set(Logger {
+            stderr: args.verbose && !args.quiet,
+            file: log_file,
+        })
+        .expect("Logger is only set once");
+    let subcommand_format = args.format.subcommand_format();
+    anyhow::ensure!(
+        subcommand_format.is_some() || (args.command.is_none() && args.bench.is_none()),
+        "--format env, --format prometheus, --format csv and --format ndjson are only \
+         supported for the device listing"
+    );
+    // The device listing uses args.format, so the value does not matter there
+    let format = subcommand_format.unwrap_or(SubcommandFormat::Text);
+    if args.include_apdu_log {
+        anyhow::ensure!(
+            (args.format == Format::Json || args.also_json.is_some())
+                && args.command.is_none()
+                && args.bench.is_none(),
+            "--include-apdu-log is only supported for the device listing with --format json or \
+             --also-json"
+        );
+        APDU_LOG
+            .set(Mutex::default())
+            .expect("APDU log is only enabled once");
+    }
+    anyhow::ensure!(
+        !args.oneline || (args.command.is_none() && args.bench.is_none()),
+        "--oneline is only supported for the device listing"
+    );
+    anyhow::ensure!(
+        !args.first_match
+            || (matches!(args.format, Format::Text | Format::Json)
+                && args.command.is_none()
+                && args.bench.is_none()),
+        "--first-match is only supported for the device listing with --format text or json"
+    );
+    anyhow::ensure!(
+        args.also_json.is_none() || (args.command.is_none() && args.bench.is_none()),
+        "--also-json is only supported for the device listing"
+    );
+    anyhow::ensure!(
+        !args.omit_empty
+            || ((args.format == Format::Json || args.also_json.is_some())
+                && args.command.is_none()
+                && args.bench.is_none()),
+        "--omit-empty is only supported for the device listing with --format json or \
+         --also-json"
+    );
+    if args.record.is_some() || args.replay.is_some() {
+        anyhow::ensure!(
+            args.command.is_none() && args.bench.is_none(),
+            "--record and --replay are only supported for the device listing"
+        );
+    }
+    if args.record.is_some() {
+        RECORDING
+            .set(Mutex::default())
+            .expect("recording is only enabled once");
+    }
+    // This has to happen before the first pcsc context is established
+    if let Some(remote) = &args.pcsc_remote {
+        let path = start_pcsc_relay(remote)?;
+        std::env::set_var("PCSCLITE_CSOCK_NAME", &path);
+        RELAY_SOCKET
+            .set(path)
+            .expect("pcsc relay is only started once");
+    }
+    let result = match &args.command {
+        Some(Command::Version { device }) => print_version(&args, *device),
+        Some(Command::Reset { device, unpower }) => reset(&args, *device, *unpower),
+        Some(Command::UpdateReady { device }) => update_ready(&args, format, *device),
+        Some(Command::AdminRaw {
+            device,
+            ins,
+            p1,
+            p2,
+            data,
+            le,
+        }) => admin_raw(&args, *device, *ins, *p1, *p2, data, *le),
+        Some(Command::Doctor) => doctor(&args, format),
+        Some(Command::ListAids) => list_aids(&args, format),
+        Some(Command::DecodeUuid { uuid }) => decode_uuid(&args, format, *uuid),
+        Some(Command::Diff { old, new }) => diff(&args, format, old, new),
+        Some(Command::Schema) => print_schema(),
+        Some(Command::Serve { port, bind }) => serve(&args, *bind, *port),
+        None => match args.bench {
+            Some(duration) => bench(&args, format, duration),
+            None => diagnose(&args),
+        },
+    };
+    if args.check_only && result.is_err() {
+        exit(2);
+    }
+    remove_relay_socket();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firmware_reader(i: u8) -> FirmwareReader {
+        FirmwareReader {
+            name: ffi::CString::new(FIRMWARE_READER_NAME).unwrap(),
+            uuid: Uuid(u128::from(i)),
+            version: None,
+            version_status: None,
+            legacy: false,
+            build_info: None,
+            provisioner: false,
+            secure_boot: Some(true),
+            init_status: Some(0),
+            needs_factory_reset: false,
+            rng_self_test: RngSelfTest::Passed,
+            touch_config: None,
+            config: None,
+            max_apdu_data_size: None,
+            driver: ReaderDriver::default(),
+            contactless: Some(false),
+            nfc_quality: None,
+            applets: Vec::new(),
+        }
+    }
+
+    fn unsupported_readers(n: u8, failure: ReaderFailure) -> Vec<UnsupportedReader> {
+        (0..n)
+            .map(|i| UnsupportedReader {
+                name: ffi::CString::new(FIRMWARE_READER_NAME).unwrap(),
+                error: anyhow::anyhow!("error {}", i),
+                failure,
+            })
+            .collect()
+    }
+
+    fn probe_options() -> ProbeOptions {
+        ProbeOptions {
+            progress: false,
+            applets: Vec::new(),
+            forced_readers: Vec::new(),
+            uuid_retries: 0,
+            uuid_retry_delay: time::Duration::ZERO,
+            nfc_quality_probes: 0,
+            #[cfg(feature = "ccid")]
+            disconnect_mode: DisconnectMode::Reset,
+            config_keys: Vec::new(),
+            strict: false,
+            compat_mode: false,
+            first_match: false,
+        }
+    }
+
+    fn exchange(request: &str, response: &str) -> RecordedExchange {
+        RecordedExchange {
+            request: request.to_owned(),
+            response: Some(response.to_owned()),
+            error: None,
+        }
+    }
+
+    fn config_exchange(key: &str, response: &str) -> RecordedExchange {
+        let request = format!("00820000{:02X}{}00", key.len(), format_hex(key.as_bytes()));
+        exchange(&request, response)
+    }
+
+    fn diagnosis(n: u8) -> Diagnosis {
+        let devices = (0..n)
+            .map(|i| Device::Firmware {
+                bus: Some(1),
+                address: Some(i),
+                path: None,
+                bcd_device: 0x0100,
+                strings: UsbStrings::default(),
+                strings_error: None,
+                descriptors: None,
+                descriptors_error: None,
+                ccid_interface: Some(true),
+            })
+            .collect();
+        let reader_status = ReaderStatus {
+            firmware_readers: (0..n).map(firmware_reader).collect(),
+            connect_failed_readers: unsupported_readers(n, ReaderFailure::ConnectFailed),
+            protocol_failed_readers: unsupported_readers(n, ReaderFailure::ProtocolFailed),
+            incompatible_readers: unsupported_readers(n, ReaderFailure::Incompatible),
+            removed_readers: Vec::new(),
+            stale_readers: Vec::new(),
+            empty_readers: Vec::new(),
+            other_readers: (0..n)
+                .map(|i| ffi::CString::new(format!("reader {}", i)).unwrap())
+                .collect(),
+        };
+        let warnings = (0..n)
+            .map(|i| Warning::UnsupportedReader {
+                reader: format!("reader {}", i),
+            })
+            .collect();
+        Diagnosis {
+            schema_version: SCHEMA_VERSION,
+            generated_at: "2022-01-01T00:00:00Z".to_owned(),
+            hostname: Some("localhost".to_owned()),
+            environment: Environment::new(true),
+            usb_backend: UsbBackend::Libusb,
+            libusb: LibusbStatus::Ok { device_count: 1 },
+            devices,
+            reader_state: reader_status.state(),
+            reader_status,
+            applet_summary: Vec::new(),
+            uuid_check: Some(UuidCheck::default()),
+            state_delta: Some(StateDelta::default()),
+            logical_devices: Vec::new(),
+            warnings,
+            apdu_log: None,
+        }
+    }
+
+    #[test]
+    fn firmware_reader_names() {
+        for name in [
+            "SoloKeys Solo 2 [CCID/ICCD Interface] 00 00",
+            "  solokeys  SOLO 2\t[CCID/ICCD Interface] (1234)",
+        ] {
+            let name = ffi::CString::new(name).unwrap();
+            assert!(is_firmware_reader_name(&name), "{:?}", name);
+        }
+        for name in ["SoloKeys Solo 2", "Nitrokey Pro 00 00"] {
+            let name = ffi::CString::new(name).unwrap();
+            assert!(!is_firmware_reader_name(&name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn json_lists_are_arrays() {
+        for n in [0, 1, 3] {
+            let json = serde_json::to_value(diagnosis(n)).unwrap();
+            for field in [
+                "devices",
+                "firmware_readers",
+                "connect_failed_readers",
+                "protocol_failed_readers",
+                "incompatible_readers",
+                "other_readers",
+                "warnings",
+            ] {
+                let value = &json[field];
+                assert!(value.is_array(), "{} is not an array for n = {}", field, n);
+                assert_eq!(value.as_array().unwrap().len(), usize::from(n));
+            }
+            for field in ["present", "missing", "unexpected"] {
+                assert!(json["uuid_check"][field].is_array());
+            }
+        }
+    }
+
+    #[test]
+    fn schema_covers_json() {
+        let schema = serde_json::to_value(diagnosis_schema()).unwrap();
+        let json = serde_json::to_value(diagnosis(1)).unwrap();
+        for field in json.as_object().unwrap().keys() {
+            assert!(
+                schema["properties"].get(field).is_some(),
+                "{} is missing in the schema",
+                field
+            );
+        }
+        assert_eq!(
+            schema["properties"]["schema_version"]["const"],
+            SCHEMA_VERSION
+        );
+    }
+
+    #[test]
+    fn no_ccid_interface() {
+        let device = |ccid_interface| Device::Firmware {
+            bus: Some(1),
+            address: Some(1),
+            path: None,
+            bcd_device: 0x0100,
+            strings: UsbStrings::default(),
+            strings_error: None,
+            descriptors: None,
+            descriptors_error: None,
+            ccid_interface,
+        };
+        let kinds = |ccid_interface, reader_status: &ReaderStatus| {
+            get_warnings(&[device(ccid_interface)], reader_status, false)
+                .iter()
+                .map(|warning| format!("{:?}", warning.kind()))
+                .collect::<Vec<_>>()
+        };
+        let no_readers = ReaderStatus::default();
+        assert_eq!(kinds(Some(false), &no_readers), ["NoCcidInterface"]);
+        assert_eq!(kinds(Some(true), &no_readers), ["UnreachableDevice"]);
+        assert_eq!(kinds(None, &no_readers), ["UnreachableDevice"]);
+
+        let connect_failed = ReaderStatus {
+            connect_failed_readers: unsupported_readers(1, ReaderFailure::ConnectFailed),
+            ..Default::default()
+        };
+        assert_eq!(
+            kinds(Some(true), &connect_failed),
+            ["UnreachableDevice", "StuckCcidInterface"]
+        );
+        assert_eq!(kinds(None, &connect_failed), ["UnreachableDevice"]);
+    }
+
+    #[test]
+    fn replay_channel() {
+        let channel = ReplayChannel::new(&[exchange("00CA004F00", "01029000"), exchange("00", "")]);
+        assert_eq!(
+            channel.exchange(&hex!("00CA004F00"), 258).unwrap(),
+            hex!("01029000")
+        );
+        assert!(channel.exchange(&hex!("01"), 2).is_err());
+        assert!(channel.exchange(&hex!("00"), 2).is_err());
+    }
+
+    #[test]
+    fn replay_readers() {
+        let reader = |name: &[u8], connect_error: Option<&str>| RecordedReader {
+            name: format_hex(name),
+            connect_error: connect_error.map(ToOwned::to_owned),
+            no_card: false,
+            max_apdu_data_size: None,
+            driver: None,
+            contactless: false,
+            exchanges: Vec::new(),
+        };
+        const SECOND_READER: &[u8] = b"SoloKeys Solo 2 [CCID/ICCD Interface] 01 00";
+        const THIRD_READER: &[u8] = b"SoloKeys Solo 2 [CCID/ICCD Interface] 02 00";
+        let recording = Recording {
+            usb: None,
+            ccid_error: None,
+            reader_names: vec![
+                format_hex(FIRMWARE_READER_NAME),
+                format_hex(b"reader 0"),
+                format_hex(SECOND_READER),
+                format_hex(THIRD_READER),
+            ],
+            readers: vec![
+                reader(FIRMWARE_READER_NAME, Some("Failed to connect")),
+                reader(SECOND_READER, None),
+                RecordedReader {
+                    no_card: true,
+                    max_apdu_data_size: Some(255),
+                    ..reader(THIRD_READER, Some("No smart card inserted"))
+                },
+            ],
+        };
+        let options = ProbeOptions {
+            applets: AidSet::Builtin.applets(&[]),
+            ..probe_options()
+        };
+        let reader_status = replay_reader_status(&recording, &options).unwrap();
+        assert!(reader_status.firmware_readers.is_empty());
+        assert_eq!(reader_status.connect_failed_readers.len(), 1);
+        assert_eq!(reader_status.protocol_failed_readers.len(), 1);
+        assert_eq!(reader_status.empty_readers.len(), 1);
+        assert_eq!(reader_status.empty_readers[0].max_apdu_data_size, Some(255));
+        assert_eq!(reader_status.other_readers.len(), 1);
+    }
+
+    #[test]
+    fn bootloader_exit_code() {
+        let mut bootloader = diagnosis(0);
+        bootloader.devices.push(Device::Bootloader {
+            vid: 0x1fc9,
+            pid: 0x0021,
+            uuid: Uuid(0),
+            version: None,
+            path: "path".to_owned(),
+            serial: None,
+            manufacturer: None,
+            product: None,
+            memory: None,
+        });
+        assert_eq!(check_exit_code(&bootloader, &BTreeSet::new()), 4);
+        assert_eq!(check_exit_code(&diagnosis(0), &BTreeSet::new()), 0);
+        let mut firmware = diagnosis(1);
+        firmware.warnings.clear();
+        firmware.devices.extend(bootloader.devices);
+        assert_eq!(check_exit_code(&firmware, &BTreeSet::new()), 0);
+    }
+
+    #[test]
+    fn json_applets_by_name() {
+        let mut reader = firmware_reader(0);
+        for (name, present) in [("admin", true), ("piv", false)] {
+            reader.applets.push(AppletStatus {
+                name: name.to_owned(),
+                aid: String::new(),
+                present,
+                blocked: false,
+                version: None,
+                pin_retries: Vec::new(),
+                fido_credentials: None,
+                openpgp: None,
+                secrets_credentials: None,
+            });
+        }
+        let json = serde_json::to_value(&reader).unwrap();
+        let applets = json["applets"].as_object().unwrap();
+        assert_eq!(applets.len(), 2);
+        assert_eq!(applets["admin"]["present"], true);
+        assert_eq!(applets["piv"]["present"], false);
+        assert!(applets["admin"].get("name").is_none());
+    }
+
+    #[test]
+    fn json_reader_names() {
+        let json = serde_json::to_value(diagnosis(1)).unwrap();
+        for field in [
+            "firmware_readers",
+            "connect_failed_readers",
+            "protocol_failed_readers",
+            "incompatible_readers",
+        ] {
+            let reader = &json[field][0];
+            assert_eq!(reader["name"], "SoloKeys Solo 2 [CCID/ICCD Interface]");
+            assert_eq!(reader["raw_name"], format_hex(FIRMWARE_READER_NAME));
+        }
+        assert_eq!(json["other_readers"][0]["name"], "reader 0");
+        assert_eq!(json["other_readers"][0]["raw_name"], "7265616465722030");
+    }
+
+    #[test]
+    fn cbor_map_lookup() {
+        // {1: ["FIDO_2_1"], 3: h'00', 0x14: 25, 0x15: 1000}
+        let data = hex!("A4 01 81 68 4649444F5F325F31 03 41 00 14 18 19 15 19 03E8");
+        assert_eq!(cbor_map_get_uint(&data, 0x14), Some(25));
+        assert_eq!(cbor_map_get_uint(&data, 0x15), Some(1000));
+        assert_eq!(cbor_map_get_uint(&data, 0x01), None);
+        assert_eq!(cbor_map_get_uint(&data, 0x02), None);
+        assert_eq!(cbor_map_get_uint(&data[..data.len() - 1], 0x15), None);
+    }
+
+    #[test]
+    fn uuid_redaction() {
+        let uuid = Uuid(0x0123456789abcdef0123456789abcdef);
+        assert_eq!(uuid.format(false), "0123456789abcdef0123456789abcdef");
+        assert_eq!(uuid.format(true), "0123************************cdef");
+    }
+
+    #[test]
+    fn bootloader_compatibility() {
+        assert_eq!(
+            check_compatibility("K3.0.0", Version::new(1, 2, 0)),
+            Compatibility::Compatible
+        );
+        assert_eq!(
+            check_compatibility("K3.0.0", Version::new(0, 1, 0)),
+            Compatibility::Incompatible
+        );
+        assert_eq!(
+            check_compatibility("K1.0.0", Version::new(1, 2, 0)),
+            Compatibility::Unknown
+        );
+    }
+
+    #[test]
+    fn unified_devices() {
+        let bootloader = Device::Bootloader {
+            vid: 0x1fc9,
+            pid: 0x0021,
+            uuid: Uuid(5),
+            version: Some("K3.0.0".to_owned()),
+            path: "path".to_owned(),
+            serial: None,
+            manufacturer: None,
+            product: None,
+            memory: None,
+        };
+
+        let single = diagnosis(1);
+        let mut devices = single.devices;
+        let readers = single.reader_status.firmware_readers;
+        let logical_devices = unify_devices(&devices, &readers);
+        assert_eq!(logical_devices.len(), 1);
+        assert_eq!(logical_devices[0].uuid, Some(Uuid(0)));
+        assert_eq!(logical_devices[0].mode, DeviceMode::Firmware);
+        assert_eq!(logical_devices[0].devices, [0]);
+        assert_eq!(logical_devices[0].readers, [0]);
+
+        devices.push(bootloader);
+        let logical_devices = unify_devices(&devices, &readers);
+        assert_eq!(logical_devices.len(), 2);
+        assert_eq!(logical_devices[1].uuid, Some(Uuid(5)));
+        assert_eq!(logical_devices[1].mode, DeviceMode::Bootloader);
+        assert_eq!(logical_devices[1].devices, [1]);
+
+        // Firmware USB devices cannot be matched with multiple readers
+        let multiple = diagnosis(2);
+        let logical_devices =
+            unify_devices(&multiple.devices, &multiple.reader_status.firmware_readers);
+        assert_eq!(logical_devices.len(), 4);
+        assert!(logical_devices[..2]
+            .iter()
+            .all(|device| device.devices.is_empty()));
+        assert!(logical_devices[2..]
+            .iter()
+            .all(|device| device.uuid.is_none()));
+    }
+
+    #[test]
+    fn uuid_parsing() {
+        let uuid = Uuid(0x0123456789abcdef0123456789abcdef);
+        for s in [
+            "0123456789abcdef0123456789abcdef",
+            "0123456789ABCDEF0123456789ABCDEF",
+            "01234567-89ab-cdef-0123-456789abcdef",
+            "0x0123456789abcdef0123456789abcdef",
+            " 0X01234567-89ab-cdef-0123-456789abcdef\n",
+        ] {
+            assert_eq!(s.parse::<Uuid>().unwrap(), uuid, "{:?}", s);
+        }
+        for s in [
+            "",
+            "0123456789abcdef",
+            "0123456789abcdef0123456789abcdef0",
+            "0123456789abcdef-0123456789abcdef",
+            "0123456789abcdef0123456789abcdeg",
+            "+123456789abcdef0123456789abcdef",
+            "0123************************cdef",
+            "0x",
+        ] {
+            assert!(s.parse::<Uuid>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn csv_rows() {
+        let mut diagnosis = diagnosis(1);
+        diagnosis.logical_devices = unify_devices(
+            &diagnosis.devices,
+            &diagnosis.reader_status.firmware_readers,
+        );
+        let mut out = Vec::new();
+        print_csv(&mut out, &diagnosis).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\"uuid\",\"mode\",\"firmware_version\",\"bus\",\"address\",\"applets\",\"reachable\"\n\
+             \"00000000000000000000000000000000\",\"firmware\",\"\",\"1\",\"0\",\"\",\"true\"\n"
+        );
+    }
+
+    #[test]
+    fn openpgp_data() {
+        assert_eq!(openpgp_format_name("Doe<<John<Paul"), "John Paul Doe");
+        assert_eq!(openpgp_format_name("Nitrokey"), "Nitrokey");
+
+        let mut data = [0; 60];
+        data[20..40].copy_from_slice(&[0xAB; 20]);
+        let fingerprints = openpgp_parse_fingerprints(&data).unwrap();
+        assert_eq!(fingerprints.len(), 1);
+        assert_eq!(fingerprints[0].key, "decryption");
+        assert_eq!(fingerprints[0].fingerprint, "AB".repeat(20));
+        assert!(openpgp_parse_fingerprints(&data[..20]).is_err());
+    }
+
+    #[test]
+    fn reader_order() {
+        let mut readers: Vec<_> = [3, 1, 2]
+            .iter()
+            .map(|i| Reader::Firmware(Box::new(firmware_reader(*i))))
+            .collect();
+        for i in [1, 0] {
+            readers.push(Reader::Unsupported(UnsupportedReader {
+                name: ffi::CString::new(FIRMWARE_READER_NAME).unwrap(),
+                error: anyhow::anyhow!("error {}", i),
+                failure: ReaderFailure::ProtocolFailed,
+            }));
+        }
+        for name in ["Reader B", "Reader A"] {
+            readers.push(Reader::Other(ffi::CString::new(name).unwrap()));
+        }
+
+        let reader_status = collect_reader_status(readers);
+        let uuids: Vec<_> = reader_status
+            .firmware_readers
+            .iter()
+            .map(|reader| reader.uuid)
+            .collect();
+        assert_eq!(uuids, [Uuid(1), Uuid(2), Uuid(3)]);
+        let errors: Vec<_> = reader_status
+            .unsupported_readers()
+            .map(|reader| reader.error.to_string())
+            .collect();
+        assert_eq!(errors, ["error 0", "error 1"]);
+        assert_eq!(
+            reader_status.other_readers,
+            [
+                ffi::CString::new("Reader A").unwrap(),
+                ffi::CString::new("Reader B").unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn oneline() {
+        assert_eq!(oneline_summary(&diagnosis(0)), "NK3: none");
+
+        let mut diagnosis = diagnosis(1);
+        diagnosis.logical_devices = unify_devices(
+            &diagnosis.devices,
+            &diagnosis.reader_status.firmware_readers,
+        );
+        assert_eq!(oneline_summary(&diagnosis), "NK3: 1! (busy)");
+        diagnosis.reader_status.connect_failed_readers.clear();
+        assert_eq!(oneline_summary(&diagnosis), "NK3: 1! (1 warning(s))");
+
+        // Info warnings are not counted
+        diagnosis.warnings = vec![Warning::MultipleDevices];
+        diagnosis.reader_status.firmware_readers[0].version = Some(Version::from(0x0040_01c0));
+        assert_eq!(oneline_summary(&diagnosis), "NK3: 1 ok (1.7.0) ✓");
+    }
+
+    #[test]
+    fn unset_uuid() {
+        let reader_status = ReaderStatus {
+            firmware_readers: vec![
+                firmware_reader(0),
+                firmware_reader(1),
+                FirmwareReader {
+                    uuid: Uuid(u128::MAX),
+                    ..firmware_reader(2)
+                },
+            ],
+            ..Default::default()
+        };
+        let uuids: Vec<_> = get_warnings(&[], &reader_status, false)
+            .iter()
+            .filter(|warning| matches!(warning, Warning::UnsetUuid { .. }))
+            .filter_map(Warning::uuid)
+            .collect();
+        assert_eq!(uuids, [Uuid(0), Uuid(u128::MAX)]);
+    }
+
+    #[test]
+    fn json_omit_empty() {
+        let mut value = serde_json::json!({
+            "a": null,
+            "b": [],
+            "c": [{"d": null, "e": 1}],
+            "f": {},
+        });
+        omit_empty(&mut value);
+        assert_eq!(value, serde_json::json!({"c": [{"e": 1}], "f": {}}));
+    }
+
+    #[test]
+    fn decode_uuid() {
+        let decoded = DecodedUuid::new("0123456789abcdef0011223344556677".parse().unwrap());
+        assert_eq!(decoded.canonical, "01234567-89ab-cdef-0011-223344556677");
+        assert_eq!(
+            decoded.words,
+            ["01234567", "89abcdef", "00112233", "44556677"]
+        );
+        assert!(decoded.interpretation.starts_with("128-bit"));
+
+        let nrf52 = Uuid(0x0123_4567_89ab_cdef << 64);
+        assert!(interpret_uuid(nrf52).starts_with("64-bit"));
+        assert!(interpret_uuid(Uuid(0)).starts_with("unset"));
+    }
+
+    #[test]
+    fn config_values() {
+        let channel = ReplayChannel::new(&[
+            config_exchange("fido.disable_skip_up_timeout", "747275659000"),
+            config_exchange("opcard.use_se050_backend", "6A88"),
+            config_exchange("custom.key", "319000"),
+        ]);
+        let config = admin_get_config_values(&channel, &["custom.key".to_owned()]).unwrap();
+        let values: Vec<_> = config
+            .iter()
+            .map(|value| {
+                (
+                    value.key.as_str(),
+                    value.description.is_some(),
+                    value.value.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("fido.disable_skip_up_timeout", true, "true"),
+                ("custom.key", false, "1")
+            ]
+        );
+        let touch_config = touch_config(&config).unwrap();
+        assert_eq!(touch_config["fido.disable_skip_up_timeout"], "true");
+    }
+
+    #[test]
+    #[cfg(feature = "ccid")]
+    fn nfc_quality() {
+        assert!(is_contactless_atr(&hex!(
+            "3B8F8001804F0CA0000003060300030000000068"
+        )));
+        assert!(!is_contactless_atr(&hex!("3BFC130000811FC7806731DF")));
+        assert_eq!(NfcQuality::new(8, 0), NfcQuality::Good);
+        assert_eq!(NfcQuality::new(8, 2), NfcQuality::Marginal);
+        assert_eq!(NfcQuality::new(8, 3), NfcQuality::Poor);
+    }
+
+    #[test]
+    fn strict_aborts_scan() {
+        let options = ProbeOptions {
+            strict: true,
+            ..probe_options()
+        };
+        let names = vec![
+            ffi::CString::new(FIRMWARE_READER_NAME).unwrap(),
+            ffi::CString::new(FIRMWARE_READER_NAME).unwrap(),
+        ];
+        let mut probed = 0;
+        let result = probe_readers(names, &options, |name| {
+            probed += 1;
+            Reader::Unsupported(UnsupportedReader {
+                name: name.to_owned(),
+                error: anyhow::anyhow!("Failed to connect"),
+                failure: ReaderFailure::ConnectFailed,
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(probed, 1);
+    }
+
+    #[test]
+    fn ndjson_lines() {
+        let mut diagnosis = diagnosis(2);
+        diagnosis.logical_devices = unify_devices(
+            &diagnosis.devices,
+            &diagnosis.reader_status.firmware_readers,
+        );
+        diagnosis.warnings.push(Warning::SecureBootDisabled {
+            uuid: Uuid(1),
+            reader: "reader 1".to_owned(),
+        });
+        let mut out = Vec::new();
+        print_ndjson(&mut out, &diagnosis).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let (summary, devices) = lines.split_last().unwrap();
+        assert_eq!(summary["type"], "summary");
+        assert_eq!(summary["device_count"], devices.len());
+        assert_eq!(summary["warnings"].as_array().unwrap().len(), 2);
+        let device_warnings: usize = devices
+            .iter()
+            .inspect(|device| assert_eq!(device["type"], "device"))
+            .map(|device| device["warnings"].as_array().unwrap().len())
+            .sum();
+        assert_eq!(device_warnings, 1);
+    }
+
+    #[test]
+    fn removed_during_scan() {
+        let name = ffi::CString::new(FIRMWARE_READER_NAME).unwrap();
+        let reader = probe_reader(&name, || {
+            Err(anyhow::anyhow!("Card removed")
+                .context(DeviceRemoved)
+                .context("Failed to query UUID"))
+        });
+        match reader {
+            Reader::Unsupported(reader) => assert_eq!(reader.failure, ReaderFailure::Removed),
+            _ => panic!("Unexpected reader {:?}", reader),
+        }
+    }
+
+    #[test]
+    fn secrets_credentials() {
+        let list = |response| exchange("00A1000000", response);
+        // One TOTP, one HOTP and one HMAC credential
+        let channel = ReplayChannel::new(&[list("720321616272021162720241639000")]);
+        let credentials = secrets_get_credentials(&channel).unwrap();
+        assert_eq!(
+            (credentials.totp, credentials.hotp, credentials.other),
+            (Some(1), Some(1), Some(1))
+        );
+
+        let channel = ReplayChannel::new(&[list("6982")]);
+        let credentials = secrets_get_credentials(&channel).unwrap();
+        assert_eq!(credentials.totp, None);
+    }
+
+    #[test]
+    fn legacy_firmware() {
+        // Only the select, UUID and version commands are sent
+        let channel = ReplayChannel::new(&[
+            exchange("00A4040009A00000084700000001", "9000"),
+            exchange("0062000010", "000102030405060708090A0B0C0D0E0F9000"),
+            exchange("0061000004", "6D00"),
+        ]);
+        let options = ProbeOptions {
+            applets: vec![Applet::new(AID_ADMIN)],
+            ..probe_options()
+        };
+        let name = ffi::CString::new(FIRMWARE_READER_NAME).unwrap();
+        let reader = probe_firmware(
+            &channel,
+            &name,
+            &options,
+            None,
+            ReaderDriver::default(),
+            Some(false),
+        )
+        .unwrap();
+        assert!(reader.legacy);
+        assert_eq!(reader.version, None);
+        assert!(reader
+            .to_string()
+            .contains("firmware legacy (version unavailable)"));
+    }
+
+    #[test]
+    #[cfg(feature = "ccid")]
+    fn admin_raw_status() {
+        let channel = ReplayChannel::new(&[
+            exchange("00A4040009A00000084700000001", "9000"),
+            exchange("0061000004", "010203049000"),
+            exchange("00A4040009A00000084700000001", "9000"),
+            exchange("00FF0000", "6D00"),
+        ]);
+        assert_eq!(
+            admin_raw_exchange(&channel, 0x61, 0x00, 0x00, &[], Some(4)).unwrap(),
+            (vec![1, 2, 3, 4], (0x90, 0x00))
+        );
+        assert_eq!(
+            admin_raw_exchange(&channel, 0xFF, 0x00, 0x00, &[], None).unwrap(),
+            (Vec::new(), (0x6D, 0x00))
+        );
+        assert!(parse_hex_byte("0102").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sysfs_devices() {
+        let root = std::env::temp_dir().join(format!("nk3-diagnose-sysfs-{}", process::id()));
+        let write = |dir: &str, name: &str, value: &str| {
+            let dir = root.join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(name), format!("{}\n", value)).unwrap();
+        };
+        write("1-2", "idVendor", "1209");
+        write("1-2", "idProduct", "beee");
+        write("1-2", "busnum", "1");
+        write("1-2", "devnum", "7");
+        write("1-2", "bcdDevice", "0107");
+        write("1-2", "product", "Nitrokey 3");
+        write("1-2/1-2:1.1", "bInterfaceClass", "0b");
+        write("1-3", "idVendor", "1d6b");
+        write("1-3", "idProduct", "0002");
+        let devices = find_firmware_devices_sysfs(&root);
+        fs::remove_dir_all(&root).unwrap();
+        match devices.unwrap().as_slice() {
+            [Device::Firmware {
+                bus,
+                address,
+                bcd_device,
+                strings,
+                ccid_interface,
+                ..
+            }] => {
+                assert_eq!((*bus, *address), (Some(1), Some(7)));
+                assert_eq!(*bcd_device, 0x0107);
+                assert_eq!(strings.product.as_deref(), Some("Nitrokey 3"));
+                assert_eq!(*ccid_interface, Some(true));
+            }
+            devices => panic!("Unexpected devices {:?}", devices),
+        }
+    }
+
+    #[test]
+    fn mixed_firmware_versions() {
+        let reader = |i, version| FirmwareReader {
+            version: Some(version),
+            ..firmware_reader(i)
+        };
+        let (old, new) = (Version::new(1, 6, 0), Version::new(1, 7, 0));
+        let mut reader_status = ReaderStatus {
+            firmware_readers: vec![reader(1, new), reader(2, old), reader(3, new)],
+            ..Default::default()
+        };
+        let warnings = get_warnings(&[], &reader_status, false);
+        let warning = warnings
+            .iter()
+            .find(|warning| matches!(warning, Warning::MixedFirmwareVersions { .. }))
+            .unwrap();
+        assert!(warning
+            .to_string()
+            .contains("v1.6.0 (1 device(s)), v1.7.0 (2 device(s))"));
+        let json = serde_json::to_value(warning).unwrap();
+        assert_eq!(json["versions"]["1.7.0"].as_array().unwrap().len(), 2);
+
+        reader_status.firmware_readers.remove(1);
+        assert!(!get_warnings(&[], &reader_status, false)
+            .iter()
+            .any(|warning| matches!(warning, Warning::MixedFirmwareVersions { .. })));
+    }
+
+    #[test]
+    fn bootloader_memory() {
+        let memory = BootloaderMemory {
+            flash_start_address: Some(0),
+            flash_size: Some(0x9_de00),
+            flash_page_size: Some(512),
+            flash_sector_size: None,
+            ram_start_address: Some(0x2000_0000),
+            ram_size: Some(0x3_0000),
+            reserved_regions: vec![(0x2000_0000, 0x2000_8000)],
+        };
+        let mut out = Vec::new();
+        print_bootloader_memory(&mut out, &memory).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "  flash: start 0x00000000, size 646656 bytes, page size 512 bytes, sector size unknown",
+                "  RAM: start 0x20000000, size 196608 bytes",
+                "  reserved region: 0x20000000-0x20008000",
+            ]
+        );
+    }
+
+    #[test]
+    fn first_match_skips_readers() {
+        let options = ProbeOptions {
+            first_match: true,
+            ..probe_options()
+        };
+        let names = vec![ffi::CString::new(FIRMWARE_READER_NAME).unwrap(); 3];
+        // The first device has an unset UUID, so the scan stops after the second one
+        let mut probed = 0;
+        let readers = probe_readers(names, &options, |_| {
+            let reader = firmware_reader(probed);
+            probed += 1;
+            Reader::Firmware(Box::new(reader))
+        })
+        .unwrap();
+        assert_eq!(readers.len(), 2);
+        assert_eq!(probed, 2);
+    }
+
+    #[test]
+    fn rng_self_test() {
+        let get_random = |response| exchange("0060000000", response);
+        let random = "000102030405060708090A0B0C0D0E0F9000";
+        let channel = ReplayChannel::new(&[
+            get_random(random),
+            get_random("0F0E0D0C0B0A090807060504030201009000"),
+        ]);
+        assert_eq!(admin_check_rng(&channel), RngSelfTest::Passed);
+        let channel = ReplayChannel::new(&[get_random(random), get_random(random)]);
+        assert_eq!(admin_check_rng(&channel), RngSelfTest::Failed);
+        let channel = ReplayChannel::new(&[get_random("6D00")]);
+        assert_eq!(admin_check_rng(&channel), RngSelfTest::NotSupported);
+    }
+
+    #[test]
+    fn diagnosis_diff() {
+        let saved = |diagnosis: &Diagnosis| -> SavedDiagnosis {
+            SavedDiagnosis::from_value(serde_json::to_value(diagnosis).unwrap()).unwrap()
+        };
+        let applet = |name: &str| AppletStatus {
+            name: name.to_owned(),
+            aid: String::new(),
+            present: true,
+            blocked: false,
+            version: None,
+            pin_retries: Vec::new(),
+            fido_credentials: None,
+            openpgp: None,
+            secrets_credentials: None,
+        };
+        let mut old = diagnosis(2);
+        old.reader_status.firmware_readers[0].version = Some(Version::new(1, 6, 0));
+        let mut new = diagnosis(2);
+        new.reader_status.firmware_readers.remove(1);
+        new.reader_status.firmware_readers.push(firmware_reader(2));
+        new.reader_status.firmware_readers[0].version = Some(Version::new(1, 7, 0));
+        new.reader_status.firmware_readers[0]
+            .applets
+            .push(applet("secrets"));
+
+        let diff = DiagnosisDiff::new(&saved(&old), &saved(&new));
+        assert_eq!(diff.added, [Uuid(2)]);
+        assert_eq!(diff.removed, [Uuid(1)]);
+        let mut out = Vec::new();
+        print_diff(&mut out, &diff).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("firmware 1.6.0 -> 1.7.0"));
+        assert!(out.contains("applet secrets gained"));
+
+        assert!(DiagnosisDiff::new(&saved(&old), &saved(&old)).is_empty());
+
+        // REDACT_UUIDS is global, so the redacted output is built manually
+        let mut redacted = serde_json::to_value(&old).unwrap();
+        redacted["firmware_readers"][0]["uuid"] = Uuid(0).format(true).into();
+        let err = SavedDiagnosis::from_value(redacted).unwrap_err();
+        assert!(err.to_string().contains("redacted"));
+    }
+
+    #[test]
+    fn rng_self_test_uses_admin_applet() {
+        let mut exchanges = vec![
+            exchange("00A4040009A00000084700000001", "9000"),
+            exchange("0062000010", "000102030405060708090A0B0C0D0E0F9000"),
+            exchange("0061000004", "000100009000"),
+            exchange("00610000010100", "6D00"),
+            exchange("0063000001", "019000"),
+            exchange("0080000000", "009000"),
+        ];
+        for (key, _) in KNOWN_CONFIG_KEYS {
+            exchanges.push(config_exchange(key, "6A88"));
+        }
+        // The random data is requested while the admin applet is still selected
+        exchanges.extend(vec![
+            exchange("0060000000", "000102030405060708090A0B0C0D0E0F9000"),
+            exchange("0060000000", "0F0E0D0C0B0A090807060504030201009000"),
+            exchange("00A4040007D276000085010100", "9000"),
+        ]);
+        let channel = ReplayChannel::new(&exchanges);
+        let options = ProbeOptions {
+            applets: vec![Applet::new(AID_ADMIN), Applet::new(AID_NDEF)],
+            ..probe_options()
+        };
+        let name = ffi::CString::new(FIRMWARE_READER_NAME).unwrap();
+        let reader = probe_firmware(
+            &channel,
+            &name,
+            &options,
+            None,
+            ReaderDriver::default(),
+            Some(false),
+        )
+        .unwrap();
+        assert_eq!(reader.rng_self_test, RngSelfTest::Passed);
+        assert!(reader.applets[1].present);
+    }
+
+    #[test]
+    fn stale_readers() {
+        let classify = |n: u8, backend| {
+            let diagnosis = diagnosis(n);
+            let mut reader_status = ReaderStatus {
+                firmware_readers: diagnosis.reader_status.firmware_readers,
+                connect_failed_readers: unsupported_readers(1, ReaderFailure::ConnectFailed),
+                ..Default::default()
+            };
+            reader_status.classify_stale_readers(&diagnosis.devices, backend);
+            assert_eq!(reader_status.state(), ReaderState::FirmwareFound);
+            (
+                reader_status.connect_failed_readers.len(),
+                reader_status.stale_readers.len(),
+            )
+        };
+        // Without any USB device, the reader might belong to a device that is not visible
+        assert_eq!(classify(0, UsbBackend::Libusb), (1, 0));
+        // All devices found via USB have a working reader
+        assert_eq!(classify(2, UsbBackend::Libusb), (0, 1));
+        assert_eq!(classify(2, UsbBackend::Hidapi), (1, 0));
+
+        // Only stale readers are still firmware readers
+        let reader_status = ReaderStatus {
+            stale_readers: unsupported_readers(1, ReaderFailure::ConnectFailed),
+            ..Default::default()
+        };
+        assert_eq!(reader_status.state(), ReaderState::FirmwareFound);
+    }
+
+    #[test]
+    fn extended_length() {
+        let transmit = |max_apdu_data_size, exchanges: &[RecordedExchange]| {
+            let channel = ReplayChannel::new(exchanges);
+            let channel = SizedChannel {
+                channel: &channel,
+                max_apdu_data_size,
+            };
+            ccid_transmit(&channel, 0x60, 0x00, 0x00, &[], Some(0)).unwrap()
+        };
+        // Readers with short APDUs only need GET RESPONSE for the remaining data
+        let chained = [
+            exchange("0060000000", "01026102"),
+            exchange("00C0000002", "03049000"),
+        ];
+        assert_eq!(transmit(None, &chained), [1, 2, 3, 4]);
+        assert_eq!(transmit(Some(255), &chained), [1, 2, 3, 4]);
+        let extended = [exchange("00600000000000", "010203049000")];
+        assert_eq!(transmit(Some(65544), &extended), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn known_buggy_version() {
+        let version = Version::new(1, 2, 0);
+        let mut known = KnownVersion {
+            min: version,
+            max: version,
+            status: VersionStatus::Supported,
+            issue: None,
+        };
+        assert!(known
+            .warning(Uuid(0), "reader".to_owned(), version)
+            .is_none());
+
+        known.issue = Some(("The example is broken.", Version::new(1, 2, 1)));
+        let warning = known
+            .warning(Uuid(0), "reader".to_owned(), version)
+            .unwrap();
+        assert_eq!(
+            warning.to_string(),
+            format!(
+                "The device with the uuid {} runs firmware v1.2.0 (known issue).  The example \
+                 is broken.  Please update to v1.2.1 or later.",
+                Uuid(0)
+            )
+        );
+    }
+
+    #[test]
+    fn update_ready_power() {
+        let power = |contactless| {
+            let reader = FirmwareReader {
+                contactless,
+                ..firmware_reader(0)
+            };
+            check_update_ready(&ReaderStatus::default(), &reader)
+                .into_iter()
+                .find(|check| check.name == "power")
+                .unwrap()
+                .status
+        };
+        assert_eq!(power(Some(false)), CheckStatus::Pass);
+        assert_eq!(power(Some(true)), CheckStatus::Warn);
+        assert_eq!(power(None), CheckStatus::Warn);
+    }
+
+    #[test]
+    fn readers_without_usb_devices() {
+        let mut diagnosis = diagnosis(0);
+        assert!(!diagnosis.has_devices());
+        // e. g. the readers of a remote pcscd with --pcsc-remote
+        diagnosis
+            .reader_status
+            .firmware_readers
+            .push(firmware_reader(0));
+        assert!(diagnosis.devices.is_empty());
+        assert!(diagnosis.has_devices());
+    }
 }